@@ -0,0 +1,178 @@
+//! Sockets handed over through socket activation, as systemd does it: they
+//! are open file descriptors from 3 on, described by the `LISTEN_PID`,
+//! `LISTEN_FDS` and `LISTEN_FDNAMES` environment variables.
+
+use std::io::ErrorKind;
+use std::os::unix::io::{FromRawFd, RawFd};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use socket2::Type;
+use tracing::debug;
+use crate::listener::{Address, Bound};
+use crate::Listener;
+
+const FIRST_FD: RawFd = 3;
+
+/// Whether the passed sockets were turned into listeners, which must only
+/// happen once since the listeners own them.
+static TAKEN: AtomicBool = AtomicBool::new(false);
+
+impl Listener {
+    /// The sockets passed to this process through socket activation, in the
+    /// order they were passed, named after `LISTEN_FDNAMES`. Without any, or
+    /// when they were passed to another process, this is empty.
+    ///
+    /// The returned listeners own the sockets, so this fails when called a
+    /// second time. The activation variables are left as they are: changing
+    /// the environment is not sound while other threads may read it, and
+    /// `LISTEN_PID` already keeps child processes from taking the sockets.
+    pub fn activated() -> std::io::Result<Vec<Listener>> {
+        let taken = || std::io::Error::new(ErrorKind::AlreadyExists, "The activated sockets were already taken");
+        if TAKEN.load(Ordering::SeqCst) {
+            return Err(taken());
+        }
+        let var = |name| std::env::var(name).ok();
+        let passed = passed(var("LISTEN_PID"), var("LISTEN_FDS"), var("LISTEN_FDNAMES"), std::process::id())?;
+        if passed.is_empty() {
+            return Ok(vec![]);
+        }
+        if TAKEN.swap(true, Ordering::SeqCst) {
+            return Err(taken());
+        }
+        passed.into_iter().map(|(fd, name)| {
+            debug!("Taking over socket {} ({})", fd, name.as_deref().unwrap_or("unnamed"));
+            // Safety: the descriptor was passed for this process to own, and
+            // it is only taken once.
+            let socket = unsafe { socket2::Socket::from_raw_fd(fd) };
+            socket.set_cloexec(true)?;
+            socket.set_nonblocking(false)?;
+            if socket.r#type()? != Type::STREAM {
+                return Err(std::io::Error::new(ErrorKind::InvalidInput, format!("Socket {} is not a stream socket", fd)));
+            }
+            let bound = match socket.local_addr()? {
+                address if address.is_unix() => Bound::Unix(socket.into(), None),
+                address if address.as_socket().is_some() => Bound::Tcp(socket.into()),
+                _ => {
+                    let message = format!("Socket {} is neither a TCP nor a Unix socket", fd);
+                    return Err(std::io::Error::new(ErrorKind::InvalidInput, message));
+                }
+            };
+            let mut listener = Listener::on(Address::Listening(Arc::new(bound)));
+            listener.name = name;
+            Ok(listener)
+        }).collect()
+    }
+}
+
+/// The descriptors passed to the process `process`, with their names, going
+/// by the values of the activation variables.
+fn passed(
+    pid: Option<String>,
+    fds: Option<String>,
+    names: Option<String>,
+    process: u32
+) -> std::io::Result<Vec<(RawFd, Option<String>)>> {
+    let invalid = |message: String| std::io::Error::new(ErrorKind::InvalidData, message);
+    let (Some(pid), Some(fds)) = (pid, fds) else {
+        return Ok(vec![]);
+    };
+    if pid.parse::<u32>().map_err(|e| invalid(format!("Invalid LISTEN_PID: {}", e)))? != process {
+        debug!("Ignoring sockets passed to process {}", pid);
+        return Ok(vec![]);
+    }
+    let count = fds.parse::<usize>().map_err(|e| invalid(format!("Invalid LISTEN_FDS: {}", e)))?;
+    let end = RawFd::try_from(count).ok()
+        .and_then(|count| FIRST_FD.checked_add(count))
+        .ok_or_else(|| invalid(format!("LISTEN_FDS is out of range: {}", count)))?;
+    let names: Vec<Option<String>> = match names {
+        Some(names) => names.split(':').map(|name| Some(name.to_string())).collect(),
+        None => vec![None; count]
+    };
+    if names.len() != count {
+        return Err(invalid(format!("LISTEN_FDNAMES has {} names for {} sockets", names.len(), count)));
+    }
+    Ok((FIRST_FD..end).zip(names).collect())
+}
+
+////////////////////////////////////////////////////////////////////////////////////////////////////
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{Read, Write};
+    use std::net::{TcpListener, TcpStream};
+    use std::os::unix::io::AsRawFd;
+    use std::os::unix::process::CommandExt;
+    use std::process::Command;
+    use std::time::Duration;
+    use crate::{Router, Server};
+
+    #[test]
+    fn activation_variables_are_parsed() {
+        let var = |value: &str| Some(value.to_string());
+        let passed = passed(var("42"), var("2"), var("http:admin"), 42).unwrap();
+        assert_eq!(passed, vec![(3, var("http")), (4, var("admin"))]);
+        assert_eq!(super::passed(var("42"), var("1"), None, 42).unwrap(), vec![(3, None)]);
+        assert!(super::passed(var("42"), var("2"), None, 7).unwrap().is_empty(), "Took another process's sockets!");
+        assert!(super::passed(None, None, None, 42).unwrap().is_empty());
+        assert!(super::passed(var("42"), var("two"), None, 42).is_err());
+        assert!(super::passed(var("42"), var("-1"), None, 42).is_err(), "A negative count was accepted!");
+        assert!(super::passed(var("42"), var(&RawFd::MAX.to_string()), None, 42).is_err(), "The count overflowed!");
+        assert!(super::passed(var("42"), var("2"), var("http"), 42).is_err(), "The missing name went unnoticed!");
+    }
+
+    /// Serves the activated socket when run by `serves_sockets_passed_to_a_child`.
+    #[test]
+    fn activated_child() {
+        if std::env::var("ACTIVATED_CHILD").is_err() {
+            return;
+        }
+        let mut listeners = Listener::activated().unwrap();
+        assert_eq!(listeners.len(), 1);
+        let name = listeners[0].name().unwrap().to_string();
+        Server::from_listener(listeners.remove(0))
+            .router(Router::new().get("/", move || name.clone()))
+            .serve()
+            .unwrap();
+    }
+
+    #[test]
+    fn serves_sockets_passed_to_a_child() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let fd = listener.as_raw_fd();
+        let mut command = Command::new("sh");
+        // The shell's own PID is the one the test binary gets, by exec.
+        command.args(["-c", "LISTEN_PID=$$ exec \"$0\" \"$@\""])
+            .arg(std::env::current_exe().unwrap())
+            .args(["activation::tests::activated_child", "--exact"])
+            .env("ACTIVATED_CHILD", "1")
+            .env("LISTEN_FDS", "1")
+            .env("LISTEN_FDNAMES", "http");
+        // Safety: only async-signal-safe calls between fork and exec.
+        unsafe {
+            command.pre_exec(move || {
+                let result = match fd {
+                    FIRST_FD => libc::fcntl(fd, libc::F_SETFD, 0),
+                    _ => libc::dup2(fd, FIRST_FD)
+                };
+                if result == -1 {
+                    return Err(std::io::Error::last_os_error());
+                }
+                Ok(())
+            });
+        }
+        let mut child = command.spawn().unwrap();
+
+        let mut stream = TcpStream::connect(address).unwrap();
+        stream.set_read_timeout(Some(Duration::from_secs(10))).unwrap();
+        stream.write_all(b"GET / HTTP/1.1\r\nConnection: close\r\n\r\n").unwrap();
+        let mut response = String::new();
+        let read = stream.read_to_string(&mut response);
+        child.kill().unwrap();
+        child.wait().unwrap();
+        read.unwrap();
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "{}", response);
+        assert!(response.ends_with("http"), "The child did not serve the passed socket!");
+    }
+}
@@ -0,0 +1,368 @@
+//! A server on the tokio runtime, for when many connections spend most of
+//! their time idle.
+//!
+//! Requests are framed, parsed, routed and serialized by the same code as in
+//! the blocking `Server`, so both answer alike. Handlers stay synchronous;
+//! they run on tokio's blocking thread pool, together with the serialization
+//! of their response, which is streamed back to the connection's task.
+
+use std::io::{BufWriter, ErrorKind, Write};
+use std::net::SocketAddr;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::{mpsc, watch};
+use tokio::task::JoinSet;
+use tracing::{debug, warn};
+use crate::extract::Peer;
+use crate::http::request::reader::RequestBuffer;
+use crate::http::response::StatusCode;
+use crate::{Limits, Router, ShutdownHandle, ShutdownSummary};
+
+/// How many bytes are requested from a connection per read.
+const CHUNK_SIZE: usize = 4096;
+
+pub struct Server {
+    address: SocketAddr,
+    limits: Limits,
+    router: Arc<Router>,
+    shutdown: ShutdownHandle,
+    shutdown_timeout: Duration,
+}
+
+impl Server {
+    pub fn new(addr: &str) -> std::io::Result<Server> {
+        let address = SocketAddr::from_str(addr)
+            .map_err(|e| std::io::Error::new(ErrorKind::InvalidInput, e.to_string()))?;
+        Ok(Server {
+            address,
+            limits: Limits::default(),
+            router: Arc::new(Router::new()),
+            shutdown: ShutdownHandle::new(),
+            shutdown_timeout: Duration::from_secs(30),
+        })
+    }
+
+    /// Sets the router that answers requests. Without one, every request is
+    /// answered with `404 Not Found`.
+    pub fn router(mut self, router: Router) -> Server {
+        self.router = Arc::new(router);
+        self
+    }
+
+    /// Sets how long a connection may sit idle waiting for its next request
+    /// before it is closed.
+    pub fn idle_timeout(mut self, idle_timeout: Duration) -> Server {
+        self.limits.idle_timeout = idle_timeout;
+        self
+    }
+
+    /// Sets how many requests are served over one connection before it is
+    /// closed. A value of one disables persistent connections.
+    pub fn max_requests(mut self, max_requests: usize) -> Server {
+        self.limits.max_requests = max_requests;
+        self
+    }
+
+    /// Sets how many bytes the request line and headers of a request may take
+    /// up together.
+    pub fn max_head_size(mut self, max_head_size: usize) -> Server {
+        self.limits.max_head_size = max_head_size;
+        self
+    }
+
+    /// Sets how many bytes a request body may take up.
+    pub fn max_body_size(mut self, max_body_size: usize) -> Server {
+        self.limits.max_body_size = max_body_size;
+        self
+    }
+
+    /// Sets whether `POST`, `PUT` and `PATCH` requests without a
+    /// `Content-Length` are answered with `411 Length Required`.
+    pub fn require_content_length(mut self, require_length: bool) -> Server {
+        self.limits.require_length = require_length;
+        self
+    }
+
+    /// Sets how long a shutdown waits for busy connections to finish before
+    /// closing them anyway.
+    pub fn shutdown_timeout(mut self, shutdown_timeout: Duration) -> Server {
+        self.shutdown_timeout = shutdown_timeout;
+        self
+    }
+
+    /// A handle that stops this server, also from other threads.
+    pub fn shutdown_handle(&self) -> ShutdownHandle {
+        self.shutdown.clone()
+    }
+
+    /// Serves connections until the server is shut down through its
+    /// `ShutdownHandle`.
+    pub async fn serve(&self) -> std::io::Result<ShutdownSummary> {
+        self.serve_listener(TcpListener::bind(self.address).await?).await
+    }
+
+    async fn serve_listener(&self, listener: TcpListener) -> std::io::Result<ShutdownSummary> {
+        let (stop, mut stopping) = watch::channel(false);
+        self.shutdown.on_shutdown(move || {
+            let _ = stop.send(true);
+        });
+        let closed_idle = Arc::new(AtomicUsize::new(0));
+        let mut connections = JoinSet::new();
+        loop {
+            let accepted = tokio::select! {
+                accepted = listener.accept() => accepted,
+                _ = stopping.wait_for(|stopping| *stopping) => break,
+            };
+            let (stream, peer) = match accepted {
+                Ok(accepted) => accepted,
+                Err(e) => {
+                    warn!("Failed to accept connection: {}", e);
+                    match crate::Server::accept_backoff(&e) {
+                        Some(backoff) => {
+                            tokio::time::sleep(backoff).await;
+                            continue;
+                        }
+                        None => return Err(e),
+                    }
+                }
+            };
+            connections.spawn(Server::handle(
+                stream,
+                peer,
+                self.limits,
+                Arc::clone(&self.router),
+                stopping.clone(),
+                Arc::clone(&closed_idle),
+            ));
+            while connections.try_join_next().is_some() {}
+        }
+        drop(listener);
+
+        let started = Instant::now();
+        let open = connections.len();
+        let deadline = tokio::time::sleep(self.shutdown_timeout);
+        tokio::pin!(deadline);
+        while !connections.is_empty() {
+            tokio::select! {
+                _ = connections.join_next() => {}
+                _ = &mut deadline => break,
+            }
+        }
+        let aborted = connections.len();
+        if aborted > 0 {
+            warn!("Closed {} connections that were still busy", aborted);
+        }
+        connections.abort_all();
+        let closed_idle = closed_idle.load(Ordering::SeqCst);
+        let summary = ShutdownSummary {
+            closed_idle,
+            drained: open.saturating_sub(closed_idle + aborted),
+            aborted,
+            elapsed: started.elapsed(),
+        };
+        debug!("Shut down: {:?}", summary);
+        Ok(summary)
+    }
+
+    async fn handle(
+        mut stream: TcpStream,
+        peer: SocketAddr,
+        limits: Limits,
+        router: Arc<Router>,
+        mut stopping: watch::Receiver<bool>,
+        closed_idle: Arc<AtomicUsize>,
+    ) {
+        let mut buffer = RequestBuffer::new(limits.max_head_size)
+            .max_body_size(limits.max_body_size)
+            .require_length(limits.require_length);
+        let mut chunk = vec![0u8; CHUNK_SIZE];
+        let mut served = 0;
+        loop {
+            loop {
+                match buffer.has_request() {
+                    Ok(true) => break,
+                    Ok(false) => {}
+                    Err(e) => {
+                        if let Some(status_code) = e.status_code() {
+                            Server::reject(&mut stream, &router, status_code).await;
+                        }
+                        warn!("Failed to handle connection: {}", e);
+                        return;
+                    }
+                }
+                // A connection waiting for anything but its first request is
+                // idle, and is closed rather than waited for when shutting down.
+                let idle = served > 0 && buffer.is_empty();
+                let read = tokio::select! {
+                    read = tokio::time::timeout(limits.idle_timeout, stream.read(&mut chunk)) => read,
+                    _ = stopping.wait_for(|stopping| *stopping), if idle => {
+                        debug!("Closing idle connection for shutdown");
+                        closed_idle.fetch_add(1, Ordering::SeqCst);
+                        return;
+                    }
+                };
+                match read {
+                    Ok(Ok(0)) => {
+                        debug!("Closing idle connection: {}", buffer.closed());
+                        return;
+                    }
+                    Ok(Ok(size)) => buffer.extend(&chunk[..size]),
+                    Ok(Err(e)) if crate::Server::is_idle(&e) => {
+                        debug!("Closing idle connection: {}", e);
+                        return;
+                    }
+                    Ok(Err(e)) => {
+                        warn!("Failed to handle connection: {}", e);
+                        return;
+                    }
+                    Err(_) => {
+                        debug!("Closing idle connection: timed out");
+                        return;
+                    }
+                }
+            }
+            let request = match crate::Server::parse(buffer.take_request().unwrap()) {
+                Ok(request) => request.into_owned(),
+                Err(e) => {
+                    if let Some(status_code) = e.status_code() {
+                        Server::reject(&mut stream, &router, status_code).await;
+                    }
+                    warn!("Failed to handle connection: {}", e);
+                    return;
+                }
+            };
+            served += 1;
+
+            let (sender, mut receiver) = mpsc::channel(16);
+            let router = Arc::clone(&router);
+            let stopping = stopping.clone();
+            let responding = tokio::task::spawn_blocking(move || {
+                let mut response = crate::Server::respond(&router, &request, Some(Peer::Tcp(peer)));
+                let keep_open = served < limits.max_requests && !*stopping.borrow();
+                let persistent = crate::Server::connection(&request, &mut response, keep_open);
+                let mut writer = BufWriter::new(ChannelWriter(sender));
+                response.serialize_for(&request.method, &mut writer).and_then(|_| writer.flush()).map(|_| persistent)
+            });
+            while let Some(bytes) = receiver.recv().await {
+                if let Err(e) = stream.write_all(&bytes).await {
+                    warn!("Failed to handle connection: {}", e);
+                    return;
+                }
+            }
+            match responding.await {
+                Ok(Ok(true)) => {}
+                Ok(Ok(false)) => return,
+                Ok(Err(e)) => {
+                    warn!("Failed to handle connection: {}", e);
+                    return;
+                }
+                Err(e) => {
+                    warn!("Failed to handle connection: {}", e);
+                    return;
+                }
+            }
+        }
+    }
+
+    /// Answers with an error page for `status_code` and closes the connection.
+    async fn reject(stream: &mut TcpStream, router: &Router, status_code: StatusCode) {
+        let mut bytes = vec![];
+        let written = crate::Server::rejection(router, status_code).serialize(&mut bytes);
+        if let Err(e) = async { written?; stream.write_all(&bytes).await }.await {
+            warn!("Failed to reject connection: {}", e);
+        }
+        // Drain whatever the client is still sending, so closing the socket
+        // does not reset the connection before the response is read.
+        let _ = stream.shutdown().await;
+        let mut discard = [0u8; 1024];
+        let drain = async { while matches!(stream.read(&mut discard).await, Ok(size) if size > 0) {} };
+        let _ = tokio::time::timeout(Duration::from_millis(100), drain).await;
+    }
+}
+
+/// Hands whatever is written to it over to a connection's task.
+struct ChannelWriter(mpsc::Sender<Vec<u8>>);
+
+impl Write for ChannelWriter {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.0.blocking_send(buf.to_vec())
+            .map_err(|_| std::io::Error::new(ErrorKind::BrokenPipe, "connection closed"))?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////////////////////////////
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::extract::Path;
+    use crate::http::response::{Body, Response};
+
+    async fn spawn_server(server: Server) -> (SocketAddr, ShutdownHandle, tokio::task::JoinHandle<ShutdownSummary>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        let handle = server.shutdown_handle();
+        let serving = tokio::spawn(async move { server.serve_listener(listener).await.unwrap() });
+        (address, handle, serving)
+    }
+
+    async fn read_response(stream: &mut TcpStream) -> String {
+        let mut head = vec![];
+        while !head.ends_with(b"\r\n\r\n") {
+            head.push(stream.read_u8().await.unwrap());
+        }
+        let head = String::from_utf8(head).unwrap();
+        let length = head.lines()
+            .find_map(|line| line.strip_prefix("Content-Length: "))
+            .map_or(0, |length| length.parse().unwrap());
+        let mut body = vec![0u8; length];
+        stream.read_exact(&mut body).await.unwrap();
+        head + std::str::from_utf8(&body).unwrap()
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn routes_like_the_blocking_server() {
+        let router = Router::new()
+            .get("/users/:id", |Path(id): Path<u64>| format!("user {}", id))
+            .get("/stream", || Response::builder().body(Body::chunks(["hello", " world"])).unwrap());
+        let (address, handle, serving) = spawn_server(Server::new("127.0.0.1:0").unwrap().router(router)).await;
+
+        let mut stream = TcpStream::connect(address).await.unwrap();
+        stream.write_all(b"GET /users/42 HTTP/1.1\r\n\r\n").await.unwrap();
+        let response = read_response(&mut stream).await;
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "{}", response);
+        assert!(response.contains("\r\nConnection: keep-alive\r\n"), "{}", response);
+        assert!(response.ends_with("user 42"));
+        stream.write_all(b"DELETE /users/42 HTTP/1.1\r\n\r\n").await.unwrap();
+        let response = read_response(&mut stream).await;
+        assert!(response.starts_with("HTTP/1.1 405 Method Not Allowed\r\n"), "{}", response);
+        assert!(response.contains("\r\nAllow: GET, HEAD\r\n"), "{}", response);
+        stream.write_all(b"GET /users/ada HTTP/1.1\r\n\r\n").await.unwrap();
+        assert!(read_response(&mut stream).await.starts_with("HTTP/1.1 400 Bad Request\r\n"));
+
+        stream.write_all(b"GET /stream HTTP/1.1\r\nConnection: close\r\n\r\n").await.unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await.unwrap();
+        assert!(
+            response.ends_with("\r\n\r\n5\r\nhello\r\n6\r\n world\r\n0\r\n\r\n"),
+            "The streamed body was not chunked: {}", response
+        );
+
+        let mut stream = TcpStream::connect(address).await.unwrap();
+        stream.write_all(b"GET /users/1 HTTP/1.1\r\n\r\n").await.unwrap();
+        read_response(&mut stream).await;
+        handle.shutdown();
+        assert_eq!(stream.read(&mut [0u8; 1]).await.unwrap(), 0, "The idle connection was not closed!");
+        let summary = serving.await.unwrap();
+        assert_eq!((summary.closed_idle, summary.aborted), (1, 0), "{:?}", summary);
+    }
+}
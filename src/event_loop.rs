@@ -0,0 +1,559 @@
+//! A backend that serves every connection from one thread, driven by epoll
+//! (or the platform's equivalent) through mio.
+//!
+//! Each connection keeps its own `RequestBuffer`, so requests arriving in
+//! pieces are put together across readiness events, and its own output
+//! buffer. While a response has not been fully written, nothing more is read
+//! from that connection, so a client that does not read its responses cannot
+//! make the server buffer without bound. A streamed body is read a piece at
+//! a time, as the socket takes the pieces before it. Handlers run on the loop
+//! thread, so one that blocks holds up every connection, as does a streamed
+//! body whose source blocks.
+
+use std::collections::HashMap;
+use std::io::{ErrorKind, Read, Write};
+use std::net::{Shutdown, SocketAddr};
+use std::panic::AssertUnwindSafe;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use mio::net::{TcpListener, TcpStream};
+use mio::{Events, Interest, Poll, Token, Waker};
+use tracing::{debug, warn};
+use crate::extract::Peer;
+use crate::http::{Header, Method};
+use crate::http::request::reader::RequestBuffer;
+use crate::http::response::Body;
+use crate::http::response::chunked::ChunkedWriter;
+use crate::listener::{Address, Bound};
+use crate::{Limits, Router, Server, ShutdownSummary};
+
+const LISTENER: Token = Token(0);
+const WAKER: Token = Token(1);
+
+/// How long a rejected connection is drained before it is closed.
+const DRAIN_TIME: Duration = Duration::from_millis(100);
+
+/// How much of a streamed body is read at a time.
+const PIECE_SIZE: usize = 16 * 1024;
+
+impl Server {
+    /// Serves connections like `serve`, but from the calling thread alone,
+    /// until the server is shut down through its `ShutdownHandle`.
+    ///
+    /// The worker and queue settings do not apply. Only a server with a single
+    /// TCP listener without TLS or the PROXY protocol can be served this way.
+    pub fn serve_event_loop(&self) -> std::io::Result<ShutdownSummary> {
+        let unsupported = |message: &str| Err(std::io::Error::new(ErrorKind::Unsupported, message.to_string()));
+        let [listener] = &self.listeners[..] else {
+            return unsupported("The event loop serves a single listener");
+        };
+        if listener.proxy.is_some() {
+            return unsupported("The event loop does not support the PROXY protocol");
+        }
+        #[cfg(feature = "tls")]
+        if listener.tls.is_some() {
+            return unsupported("The event loop does not support TLS");
+        }
+        let listener = match &listener.address {
+            Address::Tcp(address) => std::net::TcpListener::bind(address)?,
+            Address::Listening(listener) => match listener.try_clone()? {
+                Bound::Tcp(listener) => listener,
+                #[cfg(unix)]
+                Bound::Unix(..) => return unsupported("The event loop does not support Unix sockets"),
+            },
+            #[cfg(unix)]
+            Address::Unix(..) => return unsupported("The event loop does not support Unix sockets"),
+        };
+        self.serve_event_loop_on(listener)
+    }
+
+    fn serve_event_loop_on(&self, listener: std::net::TcpListener) -> std::io::Result<ShutdownSummary> {
+        let router = self.listeners[0].router.as_ref().unwrap_or(&self.router);
+        listener.set_nonblocking(true)?;
+        let mut listener = TcpListener::from_std(listener);
+        let mut poll = Poll::new()?;
+        poll.registry().register(&mut listener, LISTENER, Interest::READABLE)?;
+        let waker = Arc::new(Waker::new(poll.registry(), WAKER)?);
+        self.shutdown.on_shutdown(move || {
+            let _ = waker.wake();
+        });
+
+        let mut connections: HashMap<Token, Connection> = HashMap::new();
+        let mut next_token = WAKER.0 + 1;
+        let mut events = Events::with_capacity(1024);
+        let mut stopping: Option<(Instant, usize, usize)> = None;
+        loop {
+            let now = Instant::now();
+            let mut deadline = connections.values().map(|connection| connection.deadline).min();
+            if let Some((started, _, _)) = stopping {
+                deadline = deadline.min(Some(started + self.shutdown_timeout));
+            }
+            let timeout = deadline.map(|deadline| deadline.saturating_duration_since(now));
+            if let Err(e) = poll.poll(&mut events, timeout) {
+                if e.kind() == ErrorKind::Interrupted {
+                    continue;
+                }
+                return Err(e);
+            }
+
+            for event in events.iter() {
+                match event.token() {
+                    LISTENER => loop {
+                        let (mut stream, peer) = match listener.accept() {
+                            Ok(accepted) => accepted,
+                            Err(e) if e.kind() == ErrorKind::WouldBlock => break,
+                            Err(e) => {
+                                warn!("Failed to accept connection: {}", e);
+                                match Server::accept_backoff(&e) {
+                                    Some(backoff) => {
+                                        std::thread::sleep(backoff);
+                                        continue;
+                                    }
+                                    None => return Err(e),
+                                }
+                            }
+                        };
+                        let token = Token(next_token);
+                        next_token += 1;
+                        if let Err(e) = poll.registry().register(&mut stream, token, Interest::READABLE | Interest::WRITABLE) {
+                            warn!("Failed to register connection: {}", e);
+                            continue;
+                        }
+                        connections.insert(token, Connection::new(stream, peer, &self.limits));
+                    },
+                    WAKER => {}
+                    token => {
+                        let stopping = self.shutdown.is_shutting_down();
+                        let open = match connections.get_mut(&token) {
+                            Some(connection) => connection.process(router, &self.limits, stopping),
+                            None => continue,
+                        };
+                        if !open {
+                            connections.remove(&token);
+                        }
+                    }
+                }
+            }
+
+            let now = Instant::now();
+            connections.retain(|_, connection| {
+                if now < connection.deadline {
+                    return true;
+                }
+                debug!("Closing idle connection: timed out");
+                false
+            });
+
+            if stopping.is_none() && self.shutdown.is_shutting_down() {
+                poll.registry().deregister(&mut listener)?;
+                let open = connections.len();
+                connections.retain(|_, connection| !connection.is_idle());
+                let closed_idle = open - connections.len();
+                debug!("Closed {} idle connections for shutdown", closed_idle);
+                stopping = Some((now, closed_idle, connections.len()));
+            }
+            if let Some((started, closed_idle, in_flight)) = stopping {
+                if connections.is_empty() || now >= started + self.shutdown_timeout {
+                    let aborted = connections.len();
+                    if aborted > 0 {
+                        warn!("Closed {} connections that were still busy", aborted);
+                    }
+                    let summary = ShutdownSummary {
+                        closed_idle,
+                        drained: in_flight.saturating_sub(aborted),
+                        aborted,
+                        elapsed: started.elapsed(),
+                    };
+                    debug!("Shut down: {:?}", summary);
+                    return Ok(summary);
+                }
+            }
+        }
+    }
+}
+
+/// The state of one connection between readiness events.
+struct Connection {
+    stream: TcpStream,
+    peer: SocketAddr,
+    buffer: RequestBuffer,
+    /// The serialized response being written, and how much of it has been.
+    output: Vec<u8>,
+    written: usize,
+    /// The streamed body still to be read into the output.
+    body: Option<Streamed>,
+    served: usize,
+    /// Whether the peer has closed its side.
+    eof: bool,
+    /// Whether to close once the output has been written.
+    close: bool,
+    /// Whether to drain the input before closing, because the response is a
+    /// rejection the client may still be sending a request for.
+    drain: bool,
+    draining: bool,
+    deadline: Instant,
+    idle_timeout: Duration,
+}
+
+impl Connection {
+    fn new(stream: TcpStream, peer: SocketAddr, limits: &Limits) -> Connection {
+        Connection {
+            stream,
+            peer,
+            buffer: RequestBuffer::new(limits.max_head_size)
+                .max_body_size(limits.max_body_size)
+                .require_length(limits.require_length),
+            output: vec![],
+            written: 0,
+            body: None,
+            served: 0,
+            eof: false,
+            close: false,
+            drain: false,
+            draining: false,
+            deadline: Instant::now() + limits.idle_timeout,
+            idle_timeout: limits.idle_timeout,
+        }
+    }
+
+    /// Whether the connection is only waiting for its next request.
+    fn is_idle(&self) -> bool {
+        self.served > 0 && self.buffer.is_empty() && self.output.is_empty() && self.body.is_none() && !self.draining
+    }
+
+    /// Makes whatever progress the socket allows, returning whether the
+    /// connection is still open.
+    fn process(&mut self, router: &Router, limits: &Limits, stopping: bool) -> bool {
+        match self.advance(router, limits, stopping) {
+            Ok(open) => open,
+            Err(e) if Server::is_idle(&e) => {
+                debug!("Closing idle connection: {}", e);
+                false
+            }
+            Err(e) => {
+                warn!("Failed to handle connection: {}", e);
+                false
+            }
+        }
+    }
+
+    fn advance(&mut self, router: &Router, limits: &Limits, stopping: bool) -> std::io::Result<bool> {
+        if self.draining {
+            return self.discard();
+        }
+        loop {
+            if !self.output.is_empty() {
+                if !self.flush()? {
+                    return Ok(true);
+                }
+                if self.pull()? {
+                    continue;
+                }
+                if self.close {
+                    if !self.drain {
+                        return Ok(false);
+                    }
+                    let _ = self.stream.shutdown(Shutdown::Write);
+                    self.draining = true;
+                    self.deadline = Instant::now() + DRAIN_TIME;
+                    return self.discard();
+                }
+            }
+            match self.buffer.has_request() {
+                Ok(true) => {
+                    self.respond(router, limits, stopping)?;
+                    continue;
+                }
+                Ok(false) => {}
+                Err(e) => {
+                    warn!("Failed to handle connection: {}", e);
+                    match e.status_code() {
+                        Some(status_code) => self.reject(router, status_code),
+                        None => return Ok(false),
+                    }
+                    continue;
+                }
+            }
+            if self.eof {
+                debug!("Closing idle connection: {}", self.buffer.closed());
+                return Ok(false);
+            }
+            match self.buffer.read_from(&mut self.stream) {
+                Ok(0) => self.eof = true,
+                Ok(_) => self.deadline = Instant::now() + self.idle_timeout,
+                Err(e) if e.kind() == ErrorKind::WouldBlock => return Ok(true),
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    /// Answers the buffered request, queueing the response for writing.
+    /// Failing means the connection is to be closed.
+    fn respond(&mut self, router: &Router, limits: &Limits, stopping: bool) -> std::io::Result<()> {
+        let request = match Server::parse(self.buffer.take_request().unwrap()) {
+            Ok(request) => request,
+            Err(e) => {
+                warn!("Failed to handle connection: {}", e);
+                if let Some(status_code) = e.status_code() {
+                    self.reject(router, status_code);
+                } else {
+                    self.close = true;
+                }
+                return Ok(());
+            }
+        };
+        self.served += 1;
+        // Panics in handlers are answered by the router, but one in an error
+        // page must not take down the loop and every other connection.
+        let peer = Some(Peer::Tcp(self.peer));
+        let mut response = std::panic::catch_unwind(AssertUnwindSafe(|| Server::respond(router, &request, peer)))
+            .map_err(|_| std::io::Error::other("responding panicked"))?;
+        let keep_open = self.served < limits.max_requests && !stopping;
+        self.close = !Server::connection(&request, &mut response, keep_open);
+        match response.serialize_head(&mut self.output) {
+            // A response to HEAD only has the head it would have with its body.
+            Ok(_) if !response.has_body() || request.method == Method::HEAD => {}
+            Ok(chunked) => match response.body {
+                Body::Full(body) => self.output.extend_from_slice(&body),
+                Body::Stream { source, trailers } => self.body = Some(Streamed { source, trailers, chunked }),
+            },
+            Err(e) => return Err(e),
+        }
+        Ok(())
+    }
+
+    fn reject(&mut self, router: &Router, status_code: crate::http::response::StatusCode) {
+        self.output.clear();
+        self.written = 0;
+        self.body = None;
+        if let Err(e) = Server::rejection(router, status_code).serialize(&mut self.output) {
+            warn!("Failed to reject connection: {}", e);
+        }
+        self.close = true;
+        self.drain = true;
+    }
+
+    /// Writes as much of the output as the socket takes, returning whether
+    /// all of it has been written.
+    fn flush(&mut self) -> std::io::Result<bool> {
+        while self.written < self.output.len() {
+            match self.stream.write(&self.output[self.written..]) {
+                Ok(0) => return Err(ErrorKind::WriteZero.into()),
+                Ok(size) => {
+                    self.written += size;
+                    self.deadline = Instant::now() + self.idle_timeout;
+                }
+                Err(e) if e.kind() == ErrorKind::WouldBlock => return Ok(false),
+                Err(e) if e.kind() == ErrorKind::Interrupted => {}
+                Err(e) => return Err(e),
+            }
+        }
+        self.output.clear();
+        self.written = 0;
+        Ok(true)
+    }
+
+    /// Reads the next piece of the streamed body into the emptied output,
+    /// ending the body once its source runs dry. Returns whether there is
+    /// more output to write.
+    fn pull(&mut self) -> std::io::Result<bool> {
+        let Some(body) = &mut self.body else {
+            return Ok(false);
+        };
+        let mut piece = [0u8; PIECE_SIZE];
+        let size = loop {
+            // The source runs on the loop thread, so its panics are caught to
+            // close only this connection.
+            match std::panic::catch_unwind(AssertUnwindSafe(|| body.source.read(&mut piece))) {
+                Ok(Ok(size)) => break size,
+                Ok(Err(e)) if e.kind() == ErrorKind::Interrupted => {}
+                Ok(Err(e)) => return Err(e),
+                Err(_) => return Err(std::io::Error::other("the streamed body panicked")),
+            }
+        };
+        match (size, body.chunked) {
+            (0, true) => {
+                ChunkedWriter::new(&mut self.output).finish(&body.trailers)?;
+                self.body = None;
+            }
+            (0, false) => self.body = None,
+            (_, true) => ChunkedWriter::new(&mut self.output).write_all(&piece[..size])?,
+            (_, false) => self.output.extend_from_slice(&piece[..size]),
+        }
+        Ok(!self.output.is_empty())
+    }
+
+    /// Reads and throws away whatever the client still sends, returning
+    /// whether there may be more.
+    fn discard(&mut self) -> std::io::Result<bool> {
+        let mut discard = [0u8; 1024];
+        loop {
+            match self.stream.read(&mut discard) {
+                Ok(0) => return Ok(false),
+                Ok(_) => {}
+                Err(e) if e.kind() == ErrorKind::WouldBlock => return Ok(true),
+                Err(e) if e.kind() == ErrorKind::Interrupted => {}
+                Err(_) => return Ok(false),
+            }
+        }
+    }
+}
+
+/// A streamed body being sent, and how.
+struct Streamed {
+    source: Box<dyn Read + Send>,
+    trailers: Vec<Header<'static>>,
+    chunked: bool,
+}
+
+////////////////////////////////////////////////////////////////////////////////////////////////////
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::TcpStream;
+    use std::thread;
+    use crate::extract::Path;
+    use crate::http::response::Response;
+    use crate::ShutdownHandle;
+
+    fn spawn_server(server: Server) -> (SocketAddr, ShutdownHandle, thread::JoinHandle<ShutdownSummary>) {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let handle = server.shutdown_handle();
+        let serving = thread::spawn(move || server.serve_event_loop_on(listener).unwrap());
+        (address, handle, serving)
+    }
+
+    fn read_head(stream: &mut TcpStream) -> String {
+        let mut head = vec![];
+        let mut byte = [0u8; 1];
+        while !head.ends_with(b"\r\n\r\n") {
+            stream.read_exact(&mut byte).unwrap();
+            head.push(byte[0]);
+        }
+        String::from_utf8(head).unwrap()
+    }
+
+    fn read_response(stream: &mut TcpStream) -> String {
+        let head = read_head(stream);
+        let length = head.lines()
+            .find_map(|line| line.strip_prefix("Content-Length: "))
+            .map_or(0, |length| length.parse().unwrap());
+        let mut body = vec![0u8; length];
+        stream.read_exact(&mut body).unwrap();
+        head + std::str::from_utf8(&body).unwrap()
+    }
+
+    #[test]
+    fn serves_many_connections_from_one_thread() {
+        let router = Router::new().get("/users/:id", |Path(id): Path<u64>| format!("user {}", id));
+        let (address, handle, serving) = spawn_server(Server::new("127.0.0.1:0").unwrap().router(router));
+
+        // Requests arriving a byte at a time on several connections at once
+        // are each put together in their own buffer.
+        let mut streams: Vec<TcpStream> = (0..4).map(|_| TcpStream::connect(address).unwrap()).collect();
+        let requests: Vec<String> = (0..4).map(|id| format!("GET /users/{} HTTP/1.1\r\n\r\n", id)).collect();
+        for position in 0..requests[0].len() {
+            for (stream, request) in streams.iter_mut().zip(&requests) {
+                stream.write_all(&request.as_bytes()[position..position + 1]).unwrap();
+            }
+        }
+        for (id, stream) in streams.iter_mut().enumerate() {
+            stream.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+            let response = read_response(stream);
+            assert!(response.ends_with(&format!("user {}", id)), "{}", response);
+        }
+
+        let mut stream = TcpStream::connect(address).unwrap();
+        stream.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        stream.write_all(b"HEAD /users/7 HTTP/1.1\r\n\r\nGET /users/7 HTTP/1.1\r\n\r\n").unwrap();
+        let head = read_head(&mut stream);
+        assert!(head.contains("\r\nContent-Length: 6\r\n"), "The HEAD response lost its framing!");
+        assert!(read_response(&mut stream).ends_with("user 7"), "The HEAD response was sent with a body!");
+
+        let mut stream = TcpStream::connect(address).unwrap();
+        stream.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        stream.write_all(b"GET /users/ada HTTP/1.1\r\n\r\nGET /nowhere HTTP/1.1\r\n\r\n").unwrap();
+        assert!(read_response(&mut stream).starts_with("HTTP/1.1 400 Bad Request\r\n"));
+        assert!(read_response(&mut stream).starts_with("HTTP/1.1 404 Not Found\r\n"), "Lost a pipelined request!");
+
+        handle.shutdown();
+        let summary = serving.join().unwrap();
+        assert_eq!((summary.closed_idle, summary.aborted), (6, 0), "{:?}", summary);
+    }
+
+    #[test]
+    fn large_responses_wait_for_the_client_to_read() {
+        let body = "x".repeat(4 * 1024 * 1024);
+        let expected = body.clone();
+        let router = Router::new().get("/large", move || body.clone());
+        let (address, handle, serving) = spawn_server(Server::new("127.0.0.1:0").unwrap().router(router));
+
+        let mut slow = TcpStream::connect(address).unwrap();
+        slow.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        slow.write_all(b"GET /large HTTP/1.1\r\n\r\nGET /large HTTP/1.1\r\n\r\n").unwrap();
+        // While the first client is not reading, others are still served.
+        let mut other = TcpStream::connect(address).unwrap();
+        other.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        other.write_all(b"GET /missing HTTP/1.1\r\n\r\n").unwrap();
+        assert!(read_response(&mut other).starts_with("HTTP/1.1 404 Not Found\r\n"));
+
+        for _ in 0..2 {
+            let response = read_response(&mut slow);
+            assert!(response.ends_with(&expected), "The large response was cut short!");
+        }
+        handle.shutdown();
+        serving.join().unwrap();
+    }
+
+    #[test]
+    fn streamed_bodies_are_read_as_the_client_takes_them() {
+        let router = Router::new()
+            .get("/endless", || Response::text(Body::chunks(std::iter::repeat(vec![b'x'; 64 * 1024]))));
+        let server = Server::new("127.0.0.1:0").unwrap().router(router).shutdown_timeout(Duration::from_millis(100));
+        let (address, handle, serving) = spawn_server(server);
+
+        let mut endless = TcpStream::connect(address).unwrap();
+        endless.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        endless.write_all(b"GET /endless HTTP/1.1\r\n\r\n").unwrap();
+        let mut start = [0u8; 17];
+        endless.read_exact(&mut start).unwrap();
+        assert_eq!(&start, b"HTTP/1.1 200 OK\r\n");
+        // The body never ends, yet the loop goes on serving others.
+        let mut other = TcpStream::connect(address).unwrap();
+        other.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        other.write_all(b"GET /missing HTTP/1.1\r\n\r\n").unwrap();
+        assert!(read_response(&mut other).starts_with("HTTP/1.1 404 Not Found\r\n"));
+
+        handle.shutdown();
+        let summary = serving.join().unwrap();
+        assert_eq!(summary.aborted, 1, "{:?}", summary);
+    }
+
+    #[test]
+    fn panicking_bodies_only_close_their_connection() {
+        struct Broken;
+        impl Read for Broken {
+            fn read(&mut self, _: &mut [u8]) -> std::io::Result<usize> {
+                panic!("stream failed")
+            }
+        }
+        let router = Router::new()
+            .get("/broken", || Response::text(Body::stream(Broken)))
+            .get("/", || "still serving");
+        let (address, handle, serving) = spawn_server(Server::new("127.0.0.1:0").unwrap().router(router));
+
+        let mut broken = TcpStream::connect(address).unwrap();
+        broken.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        broken.write_all(b"GET /broken HTTP/1.1\r\n\r\n").unwrap();
+        let mut rest = vec![];
+        let _ = broken.read_to_end(&mut rest);
+        let mut other = TcpStream::connect(address).unwrap();
+        other.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        other.write_all(b"GET / HTTP/1.1\r\n\r\n").unwrap();
+        assert!(read_response(&mut other).ends_with("still serving"), "The loop died with the stream!");
+        handle.shutdown();
+        serving.join().unwrap();
+    }
+}
@@ -0,0 +1,398 @@
+//! Typed access to the parts of a request, for use as handler arguments.
+//!
+//! Every argument of a handler implements `FromRequest`. When any of them
+//! cannot be extracted, the handler is not called and the request is answered
+//! with the status code of the `Rejection` instead, usually `400 Bad Request`,
+//! on the router's error page for it.
+
+use std::fmt::{Display, Formatter};
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use tracing::debug;
+use crate::handler::IntoResponse;
+use crate::http::QueryString;
+use crate::http::request::Request;
+use crate::http::response::{Response, StatusCode};
+use crate::router::{ErrorPages, Params, Unrouted};
+
+/// What the server knows about a request besides the request itself.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Context {
+    /// The path parameters captured by the matching route.
+    pub params: Params,
+    /// The client the request came from, if known.
+    pub peer: Option<Peer>,
+    pub(crate) error_pages: ErrorPages,
+    pub(crate) unrouted: Option<Unrouted>
+}
+
+/// The client end of a connection.
+#[derive(Clone, Debug, Eq, Hash, PartialEq)]
+pub enum Peer {
+    Tcp(SocketAddr),
+    /// A client connected over a Unix domain socket, with the path its own
+    /// socket is bound to. Clients rarely bind theirs, so this is usually
+    /// `None`.
+    Unix(Option<PathBuf>),
+    /// A client whose connection was passed on by a trusted proxy, as the
+    /// proxy's PROXY protocol header tells it: the client, the address it
+    /// connected to, and the proxy itself.
+    Proxied {
+        client: SocketAddr,
+        destination: SocketAddr,
+        proxy: SocketAddr
+    }
+}
+
+/// A value that can be taken from a request.
+pub trait FromRequest: Sized {
+    fn from_request(request: &Request<'_>, context: &Context) -> Result<Self, Rejection>;
+}
+
+/// Why a value could not be taken from a request.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Rejection {
+    status_code: StatusCode,
+    reason: String
+}
+
+impl Rejection {
+    pub fn new<R: Into<String>>(status_code: StatusCode, reason: R) -> Rejection {
+        Rejection { status_code, reason: reason.into() }
+    }
+
+    /// A `400 Bad Request` rejection.
+    pub fn bad_request<R: Into<String>>(reason: R) -> Rejection {
+        Rejection::new(StatusCode::BadRequest, reason)
+    }
+
+    pub fn status_code(&self) -> StatusCode {
+        self.status_code
+    }
+
+    pub fn reason(&self) -> &str {
+        &self.reason
+    }
+
+    /// Answers the request on the error page of the router handling it.
+    pub(crate) fn into_error_page(self, context: &Context) -> Response<'static> {
+        debug!("Rejecting request: {}", self);
+        context.error_pages.render(self.status_code)
+    }
+}
+
+impl Display for Rejection {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} ({})", self.reason, u16::from(self.status_code))
+    }
+}
+
+impl std::error::Error for Rejection {}
+
+impl IntoResponse for Rejection {
+    fn into_response(self) -> Response<'static> {
+        debug!("Rejecting request: {}", self);
+        Response::error(self.status_code)
+    }
+}
+
+impl FromRequest for Request<'static> {
+    fn from_request(request: &Request<'_>, _: &Context) -> Result<Self, Rejection> {
+        Ok(request.clone().into_owned())
+    }
+}
+
+/// The decoded query string, empty for requests without a path.
+impl FromRequest for QueryString<'static> {
+    fn from_request(request: &Request<'_>, _: &Context) -> Result<Self, Rejection> {
+        Ok(request.endpoint().map(|endpoint| endpoint.query.clone().into_owned()).unwrap_or_default())
+    }
+}
+
+impl FromRequest for Params {
+    fn from_request(_: &Request<'_>, context: &Context) -> Result<Self, Rejection> {
+        Ok(context.params.clone())
+    }
+}
+
+impl FromRequest for Peer {
+    fn from_request(_: &Request<'_>, context: &Context) -> Result<Self, Rejection> {
+        context.peer.clone().ok_or_else(|| Rejection::new(StatusCode::InternalServerError, "the peer is unknown"))
+    }
+}
+
+/// The address of a client connected over TCP, through a proxy or not.
+/// Requests from other clients are rejected.
+impl FromRequest for SocketAddr {
+    fn from_request(_: &Request<'_>, context: &Context) -> Result<Self, Rejection> {
+        match context.peer {
+            Some(Peer::Tcp(address) | Peer::Proxied { client: address, .. }) => Ok(address),
+            _ => Err(Rejection::new(StatusCode::InternalServerError, "the peer address is unknown"))
+        }
+    }
+}
+
+/// The body as raw bytes.
+impl FromRequest for Vec<u8> {
+    fn from_request(request: &Request<'_>, _: &Context) -> Result<Self, Rejection> {
+        Ok(request.body.to_vec())
+    }
+}
+
+/// The body as text. Bodies that are not valid UTF-8 are rejected.
+impl FromRequest for String {
+    fn from_request(request: &Request<'_>, _: &Context) -> Result<Self, Rejection> {
+        request.body_str()
+            .map(str::to_string)
+            .map_err(|e| Rejection::bad_request(format!("the body is not valid UTF-8: {}", e)))
+    }
+}
+
+/// Extracts `T` if it can, and `None` otherwise.
+impl<T: FromRequest> FromRequest for Option<T> {
+    fn from_request(request: &Request<'_>, context: &Context) -> Result<Self, Rejection> {
+        Ok(T::from_request(request, context).ok())
+    }
+}
+
+/// The path parameters captured by the route, parsed as `T`.
+///
+/// `T` is either a single value, for routes with one parameter, or a tuple
+/// with one value for each parameter, in the order they appear in the path.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Path<T>(pub T);
+
+/// Values that path parameters can be parsed into.
+pub trait FromParams: Sized {
+    fn from_params(params: &Params) -> Result<Self, String>;
+}
+
+impl<T: FromParams> FromRequest for Path<T> {
+    fn from_request(_: &Request<'_>, context: &Context) -> Result<Self, Rejection> {
+        T::from_params(&context.params)
+            .map(Path)
+            .map_err(|e| Rejection::bad_request(format!("invalid path parameters: {}", e)))
+    }
+}
+
+/// Parses the value of the only parameter, or of the `index`th of `count`.
+fn parse_param<T>(params: &Params, index: usize, count: usize) -> Result<T, String>
+where
+    T: std::str::FromStr,
+    T::Err: Display
+{
+    let length = params.iter().count();
+    if length != count {
+        return Err(format!("expected {} parameters, but the route has {}", count, length));
+    }
+    let (name, value) = params.iter().nth(index).unwrap();
+    value.parse().map_err(|e| format!("{}: {}", name, e))
+}
+
+macro_rules! impl_from_params {
+    ($($ty:ty),*) => {
+        $(
+            impl FromParams for $ty {
+                fn from_params(params: &Params) -> Result<Self, String> {
+                    parse_param(params, 0, 1)
+                }
+            }
+        )*
+    };
+}
+
+impl_from_params!(
+    String, bool, char,
+    u8, u16, u32, u64, u128, usize,
+    i8, i16, i32, i64, i128, isize,
+    f32, f64
+);
+
+macro_rules! impl_from_params_for_tuple {
+    ($count:literal; $($ty:ident $index:literal),*) => {
+        impl<$($ty),*> FromParams for ($($ty,)*)
+        where
+            $($ty: std::str::FromStr, $ty::Err: Display,)*
+        {
+            fn from_params(params: &Params) -> Result<Self, String> {
+                Ok(($(parse_param::<$ty>(params, $index, $count)?,)*))
+            }
+        }
+    };
+}
+
+impl_from_params_for_tuple!(1; A 0);
+impl_from_params_for_tuple!(2; A 0, B 1);
+impl_from_params_for_tuple!(3; A 0, B 1, C 2);
+impl_from_params_for_tuple!(4; A 0, B 1, C 2, D 3);
+
+/// The query string, deserialized into `T`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Query<T>(pub T);
+
+impl<T: DeserializeOwned> FromRequest for Query<T> {
+    fn from_request(request: &Request<'_>, _: &Context) -> Result<Self, Rejection> {
+        let query = request.endpoint().and_then(|endpoint| endpoint.raw_query.as_deref());
+        serde_urlencoded::from_str(query.unwrap_or_default())
+            .map(Query)
+            .map_err(|e| Rejection::bad_request(format!("invalid query string: {}", e)))
+    }
+}
+
+/// A body of `application/x-www-form-urlencoded` fields, deserialized into
+/// `T`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Form<T>(pub T);
+
+impl<T: DeserializeOwned> FromRequest for Form<T> {
+    fn from_request(request: &Request<'_>, _: &Context) -> Result<Self, Rejection> {
+        serde_urlencoded::from_bytes(&request.body)
+            .map(Form)
+            .map_err(|e| Rejection::bad_request(format!("invalid form body: {}", e)))
+    }
+}
+
+/// A JSON body deserialized into `T`, or a value of `T` serialized as a JSON
+/// response.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Json<T>(pub T);
+
+impl<T: DeserializeOwned> FromRequest for Json<T> {
+    fn from_request(request: &Request<'_>, _: &Context) -> Result<Self, Rejection> {
+        serde_json::from_slice(&request.body)
+            .map(Json)
+            .map_err(|e| Rejection::bad_request(format!("invalid JSON body: {}", e)))
+    }
+}
+
+impl<T: Serialize> IntoResponse for Json<T> {
+    fn into_response(self) -> Response<'static> {
+        match serde_json::to_vec(&self.0) {
+            Ok(body) => Response::json(body),
+            Err(e) => Rejection::new(StatusCode::InternalServerError, e.to_string()).into_response()
+        }
+    }
+}
+
+/// A header that can be extracted by name with `TypedHeader`.
+pub trait FromHeader: Sized {
+    const NAME: &'static str;
+
+    fn from_header(value: &[u8]) -> Option<Self>;
+}
+
+/// The value of the header `H` names. Requests without it are rejected.
+#[derive(Clone, Debug, PartialEq)]
+pub struct TypedHeader<H>(pub H);
+
+impl<H: FromHeader> FromRequest for TypedHeader<H> {
+    fn from_request(request: &Request<'_>, _: &Context) -> Result<Self, Rejection> {
+        let value = request.header(H::NAME)
+            .ok_or_else(|| Rejection::bad_request(format!("missing {} header", H::NAME)))?;
+        H::from_header(value)
+            .map(TypedHeader)
+            .ok_or_else(|| Rejection::bad_request(format!("invalid {} header", H::NAME)))
+    }
+}
+
+macro_rules! text_header {
+    ($(#[$doc:meta])* $ty:ident, $name:literal) => {
+        $(#[$doc])*
+        #[derive(Clone, Debug, PartialEq)]
+        pub struct $ty(pub String);
+
+        impl FromHeader for $ty {
+            const NAME: &'static str = $name;
+
+            fn from_header(value: &[u8]) -> Option<Self> {
+                std::str::from_utf8(value).ok().map(|value| $ty(value.to_string()))
+            }
+        }
+    };
+}
+
+text_header!(
+    /// The `Host` header.
+    Host, "Host"
+);
+text_header!(
+    /// The `User-Agent` header.
+    UserAgent, "User-Agent"
+);
+text_header!(
+    /// The `Content-Type` header.
+    ContentType, "Content-Type"
+);
+text_header!(
+    /// The `Authorization` header.
+    Authorization, "Authorization"
+);
+
+////////////////////////////////////////////////////////////////////////////////////////////////////
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde::Deserialize;
+    use crate::http::Header;
+
+    #[derive(Debug, Deserialize, PartialEq)]
+    struct Filter {
+        name: String,
+        limit: u32
+    }
+
+    fn context(params: &[(&str, &str)]) -> Context {
+        let mut context = Context { peer: "127.0.0.1:4000".parse().ok().map(Peer::Tcp), ..Context::default() };
+        for (name, value) in params {
+            context.params.push(name, value);
+        }
+        context
+    }
+
+    #[test]
+    fn extractors_work() {
+        let mut request = Request::try_from("POST /users/42/posts/7?name=ada&limit=10 HTTP/1.1\r\n\r\n").unwrap();
+        request.headers.push(Header::new("User-Agent", b"curl/8.0"));
+        request.body = br#"{"name":"grace","limit":3}"#.into();
+        let context = context(&[("user", "42"), ("post", "7")]);
+
+        let Path((user, post)) = Path::<(u64, u32)>::from_request(&request, &context).unwrap();
+        assert_eq!((user, post), (42, 7), "The path parameters were not parsed!");
+        assert!(Path::<u64>::from_request(&request, &context).is_err());
+
+        let Query(filter) = Query::<Filter>::from_request(&request, &context).unwrap();
+        assert_eq!(filter, Filter { name: "ada".to_string(), limit: 10 }, "The query was not parsed!");
+        let query = QueryString::from_request(&request, &context).unwrap();
+        assert_eq!(query.parse::<u32>("limit"), Ok(Some(10)));
+        let Json(filter) = Json::<Filter>::from_request(&request, &context).unwrap();
+        assert_eq!(filter, Filter { name: "grace".to_string(), limit: 3 }, "The body was not parsed!");
+        assert!(Form::<Filter>::from_request(&request, &context).is_err());
+
+        let TypedHeader(UserAgent(agent)) = TypedHeader::<UserAgent>::from_request(&request, &context).unwrap();
+        assert_eq!(agent, "curl/8.0");
+        let rejection = TypedHeader::<Host>::from_request(&request, &context).unwrap_err();
+        assert_eq!(rejection.status_code(), StatusCode::BadRequest);
+        assert_eq!(Option::<TypedHeader<Host>>::from_request(&request, &context), Ok(None));
+
+        let peer = SocketAddr::from_request(&request, &context).unwrap();
+        assert_eq!(peer, "127.0.0.1:4000".parse().unwrap());
+        let context = Context { peer: Some(Peer::Unix(None)), ..context };
+        assert_eq!(Peer::from_request(&request, &context), Ok(Peer::Unix(None)));
+        assert!(SocketAddr::from_request(&request, &context).is_err(), "A Unix peer had a TCP address!");
+    }
+
+    #[test]
+    fn form_bodies_are_extracted() {
+        let mut request = Request::try_from("POST /search HTTP/1.1\r\n\r\n").unwrap();
+        request.body = b"name=grace+hopper&limit=3".into();
+        let Form(filter) = Form::<Filter>::from_request(&request, &Context::default()).unwrap();
+        assert_eq!(filter, Filter { name: "grace hopper".to_string(), limit: 3 });
+
+        request.body = vec![0xff, 0xfe].into();
+        assert!(String::from_request(&request, &Context::default()).is_err(), "Accepted a body that is not UTF-8!");
+        assert_eq!(Vec::<u8>::from_request(&request, &Context::default()), Ok(vec![0xff, 0xfe]));
+    }
+}
@@ -0,0 +1,95 @@
+use crate::extract::{Context, FromRequest};
+use crate::http::request::Request;
+use crate::http::response::{Response, StatusCode};
+
+/// Something that can answer a request.
+///
+/// This is implemented for every function or closure whose arguments all
+/// implement `FromRequest` and whose return value implements `IntoResponse`,
+/// so such functions can be registered with a `Router` directly. `Args` is
+/// the tuple of argument types; it only tells the implementations apart.
+pub trait Handler<Args>: Send + Sync + 'static {
+    fn call(&self, request: &Request<'_>, context: &Context) -> Response<'static>;
+}
+
+macro_rules! impl_handler {
+    ($($arg:ident),*) => {
+        impl<F, R, $($arg),*> Handler<($($arg,)*)> for F
+        where
+            F: Fn($($arg),*) -> R + Send + Sync + 'static,
+            R: IntoResponse,
+            $($arg: FromRequest,)*
+        {
+            #[allow(non_snake_case, unused_variables)]
+            fn call(&self, request: &Request<'_>, context: &Context) -> Response<'static> {
+                $(
+                    let $arg = match $arg::from_request(request, context) {
+                        Ok(value) => value,
+                        Err(rejection) => return rejection.into_error_page(context)
+                    };
+                )*
+                self($($arg),*).into_response()
+            }
+        }
+    };
+}
+
+impl_handler!();
+impl_handler!(A);
+impl_handler!(A, B);
+impl_handler!(A, B, C);
+impl_handler!(A, B, C, D);
+impl_handler!(A, B, C, D, E);
+impl_handler!(A, B, C, D, E, G);
+impl_handler!(A, B, C, D, E, G, H);
+impl_handler!(A, B, C, D, E, G, H, I);
+
+/// A value a handler can return.
+pub trait IntoResponse {
+    fn into_response(self) -> Response<'static>;
+}
+
+impl IntoResponse for Response<'static> {
+    fn into_response(self) -> Response<'static> {
+        self
+    }
+}
+
+/// A plain text response.
+impl IntoResponse for &'static str {
+    fn into_response(self) -> Response<'static> {
+        Response::text(self)
+    }
+}
+
+/// A plain text response.
+impl IntoResponse for String {
+    fn into_response(self) -> Response<'static> {
+        Response::text(self)
+    }
+}
+
+/// An empty response with the status code.
+impl IntoResponse for StatusCode {
+    fn into_response(self) -> Response<'static> {
+        Response::empty(self)
+    }
+}
+
+/// The response `R` makes, with its status code replaced.
+impl<R: IntoResponse> IntoResponse for (StatusCode, R) {
+    fn into_response(self) -> Response<'static> {
+        let mut response = self.1.into_response();
+        response.status_code = self.0;
+        response
+    }
+}
+
+impl<T: IntoResponse, E: IntoResponse> IntoResponse for Result<T, E> {
+    fn into_response(self) -> Response<'static> {
+        match self {
+            Ok(value) => value.into_response(),
+            Err(error) => error.into_response()
+        }
+    }
+}
@@ -1,17 +1,54 @@
+use std::borrow::Cow;
 use std::fmt::{Display, Formatter};
+use crate::http::request::parser::{percent_decode, ParseError};
 
 pub mod request;
 pub mod response;
 
+/// A name and a value, such as a header or a query parameter.
+///
+/// Names are always ASCII text. Values are kept as the raw bytes the client
+/// sent, since header values may carry `obs-text`; `value_str` gives a
+/// checked view of them as text.
+///
+/// Both either borrow from the buffer a request was parsed from or own their
+/// data; `into_owned` turns the former into the latter.
 #[derive(Clone, Debug, PartialEq)]
 pub struct Field<'a> {
-    pub(crate) name: &'a str,
-    pub(crate) value: &'a str
+    pub name: Cow<'a, str>,
+    pub value: Cow<'a, [u8]>
+}
+
+impl<'a> Field<'a> {
+    pub fn new<N, V>(name: N, value: V) -> Field<'a>
+    where
+        N: Into<Cow<'a, str>>,
+        V: Into<Cow<'a, [u8]>>
+    {
+        Field { name: name.into(), value: value.into() }
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn value(&self) -> &[u8] {
+        &self.value
+    }
+
+    /// The value as text, if it is valid UTF-8.
+    pub fn value_str(&self) -> Result<&str, std::str::Utf8Error> {
+        std::str::from_utf8(&self.value)
+    }
+
+    pub fn into_owned(self) -> Field<'static> {
+        Field { name: Cow::Owned(self.name.into_owned()), value: Cow::Owned(self.value.into_owned()) }
+    }
 }
 
 impl<'a> Display for Field<'a> {
     fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
-        write!(f, "{}: {}", self.name, self.value)
+        write!(f, "{}: {}", self.name, String::from_utf8_lossy(&self.value))
     }
 }
 
@@ -26,12 +63,29 @@ pub enum Method<'a> {
     OPTIONS,
     TRACE,
     PATCH,
-    OTHER(&'a str)
+    OTHER(Cow<'a, str>)
+}
+
+impl<'a> Method<'a> {
+    pub fn into_owned(self) -> Method<'static> {
+        match self {
+            Method::GET => Method::GET,
+            Method::HEAD => Method::HEAD,
+            Method::POST => Method::POST,
+            Method::PUT => Method::PUT,
+            Method::DELETE => Method::DELETE,
+            Method::CONNECT => Method::CONNECT,
+            Method::OPTIONS => Method::OPTIONS,
+            Method::TRACE => Method::TRACE,
+            Method::PATCH => Method::PATCH,
+            Method::OTHER(method) => Method::OTHER(Cow::Owned(method.into_owned()))
+        }
+    }
 }
 
 impl<'a> Default for Method<'a> {
     fn default() -> Self {
-        Method::OTHER(&"")
+        Method::OTHER(Cow::Borrowed(""))
     }
 }
 
@@ -52,36 +106,301 @@ impl<'a> Display for Method<'a> {
     }
 }
 
-#[derive(Clone, Debug, Default)]
+/// The path and query of a request target, in origin or absolute form.
+///
+/// The path and query are kept both as sent and percent-decoded: `raw_path`
+/// and `raw_query` hold them as sent, while `segments` and `query` are
+/// decoded, so `/a%20b?q=x%26y` has the segment `a b` and the parameter `q`
+/// with the value `x&y`.
+#[derive(Clone, Debug, Default, PartialEq)]
 pub struct Endpoint<'a> {
-    pub segments: Vec<&'a str>,
-    pub parameters: Vec<RequestParameter<'a>>,
-    pub fragment: Option<&'a str>
+    /// The path as sent, starting with `/`.
+    pub raw_path: Cow<'a, str>,
+    /// The query as sent, without its `?`.
+    pub raw_query: Option<Cow<'a, str>>,
+    /// The decoded path segments.
+    pub segments: Vec<Cow<'a, str>>,
+    /// The decoded query parameters.
+    pub query: QueryString<'a>,
+    pub fragment: Option<Cow<'a, str>>
+}
+
+impl<'a> Endpoint<'a> {
+    pub fn into_owned(self) -> Endpoint<'static> {
+        Endpoint {
+            raw_path: Cow::Owned(self.raw_path.into_owned()),
+            raw_query: self.raw_query.map(|query| Cow::Owned(query.into_owned())),
+            segments: self.segments.into_iter().map(|segment| Cow::Owned(segment.into_owned())).collect(),
+            query: self.query.into_owned(),
+            fragment: self.fragment.map(|fragment| Cow::Owned(fragment.into_owned()))
+        }
+    }
+}
+
+impl<'a> Display for Endpoint<'a> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.raw_path)?;
+        if let Some(query) = &self.raw_query {
+            write!(f, "?{}", query)?;
+        }
+        Ok(())
+    }
 }
 
 pub type RequestParameter<'a> = Field<'a>;
 
+/// The parameters of a query string, decoded like an HTML form: `+` is a
+/// space and `%XX` escapes are decoded.
+///
+/// The parameters are kept in the order they were sent, so a name may come
+/// more than once, as in `tags=x&tags=y`. A parameter without a `=`, like
+/// `flag`, has an empty value; empty pairs, as in `a=1&&b=2`, are skipped.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct QueryString<'a> {
+    parameters: Vec<RequestParameter<'a>>
+}
+
+impl<'a> QueryString<'a> {
+    /// Decodes `query`, given without its `?`, borrowing the names and values
+    /// that needed no decoding. Fails at a malformed escape, or at a name that
+    /// does not decode to UTF-8, with its offset into `query`.
+    pub fn decode(query: &'a str) -> Result<QueryString<'a>, ParseError> {
+        let failed = |part: &str, offset: usize| ParseError::InvalidTarget {
+            offset: part.as_ptr() as usize - query.as_ptr() as usize + offset
+        };
+        let mut parameters = vec![];
+        for pair in query.split('&').filter(|pair| !pair.is_empty()) {
+            let (name, value) = pair.split_once('=').unwrap_or((pair, ""));
+            let decoded = form_decode(name.as_bytes()).map_err(|offset| failed(name, offset))?;
+            let name = match decoded {
+                Cow::Borrowed(_) => Cow::Borrowed(name),
+                Cow::Owned(decoded) => Cow::Owned(String::from_utf8(decoded).map_err(|_| failed(name, 0))?)
+            };
+            let value = form_decode(value.as_bytes()).map_err(|offset| failed(value, offset))?;
+            parameters.push(RequestParameter::new(name, value));
+        }
+        Ok(QueryString { parameters })
+    }
+
+    /// The value of the first parameter called `name`.
+    pub fn get(&self, name: &str) -> Option<&[u8]> {
+        self.parameters.iter()
+            .find(|parameter| parameter.name == name)
+            .map(Field::value)
+    }
+
+    /// The values of every parameter called `name`, in order.
+    pub fn get_all<'s>(&'s self, name: &'s str) -> impl Iterator<Item = &'s [u8]> + 's {
+        self.parameters.iter()
+            .filter(move |parameter| parameter.name == name)
+            .map(Field::value)
+    }
+
+    /// The value of the first parameter called `name`, parsed as `T`, or
+    /// `None` if there is no such parameter.
+    pub fn parse<T>(&self, name: &str) -> Result<Option<T>, String>
+    where
+        T: std::str::FromStr,
+        T::Err: Display
+    {
+        let Some(value) = self.get(name) else {
+            return Ok(None);
+        };
+        let value = std::str::from_utf8(value).map_err(|_| format!("{} is not text", name))?;
+        value.parse().map(Some).map_err(|e| format!("invalid {} {:?}: {}", name, value, e))
+    }
+
+    /// Whether a parameter called `name` was sent, with a value or without.
+    pub fn contains(&self, name: &str) -> bool {
+        self.get(name).is_some()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &RequestParameter<'a>> {
+        self.parameters.iter()
+    }
+
+    pub fn len(&self) -> usize {
+        self.parameters.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.parameters.is_empty()
+    }
+
+    pub fn into_owned(self) -> QueryString<'static> {
+        QueryString { parameters: self.parameters.into_iter().map(Field::into_owned).collect() }
+    }
+}
+
+impl<'a> From<Vec<RequestParameter<'a>>> for QueryString<'a> {
+    fn from(parameters: Vec<RequestParameter<'a>>) -> Self {
+        QueryString { parameters }
+    }
+}
+
+/// Decodes `input` like a form field, borrowing it if nothing needs decoding.
+/// Fails with the offset of the first malformed escape.
+fn form_decode(input: &[u8]) -> Result<Cow<'_, [u8]>, usize> {
+    if !input.contains(&b'+') {
+        return percent_decode(input);
+    }
+    let spaced: Vec<u8> = input.iter().map(|&byte| if byte == b'+' { b' ' } else { byte }).collect();
+    Ok(Cow::Owned(percent_decode(&spaced)?.into_owned()))
+}
+
+/// What a request is for, in one of the four forms of RFC 9112.
+#[derive(Clone, Debug, PartialEq)]
+pub enum RequestTarget<'a> {
+    /// A path and query, like `/index.html?page=2`, as sent to an origin
+    /// server.
+    Origin(Endpoint<'a>),
+    /// A full URI, like `http://example.com/index.html`, as sent to a proxy.
+    /// An origin server takes the endpoint as if it had come in origin form.
+    Absolute {
+        scheme: Cow<'a, str>,
+        authority: Authority<'a>,
+        endpoint: Endpoint<'a>
+    },
+    /// The host and port a `CONNECT` request asks to be tunnelled to, like
+    /// `example.com:443`.
+    Authority(Authority<'a>),
+    /// The `*` of an `OPTIONS` request for the server as a whole.
+    Asterisk
+}
+
+impl<'a> RequestTarget<'a> {
+    /// The path and query, for the origin and absolute forms.
+    pub fn endpoint(&self) -> Option<&Endpoint<'a>> {
+        match self {
+            RequestTarget::Origin(endpoint) | RequestTarget::Absolute { endpoint, .. } => Some(endpoint),
+            RequestTarget::Authority(_) | RequestTarget::Asterisk => None
+        }
+    }
+
+    /// The host and port, for the absolute and authority forms.
+    pub fn authority(&self) -> Option<&Authority<'a>> {
+        match self {
+            RequestTarget::Absolute { authority, .. } | RequestTarget::Authority(authority) => Some(authority),
+            RequestTarget::Origin(_) | RequestTarget::Asterisk => None
+        }
+    }
+
+    pub fn into_owned(self) -> RequestTarget<'static> {
+        match self {
+            RequestTarget::Origin(endpoint) => RequestTarget::Origin(endpoint.into_owned()),
+            RequestTarget::Absolute { scheme, authority, endpoint } => RequestTarget::Absolute {
+                scheme: Cow::Owned(scheme.into_owned()),
+                authority: authority.into_owned(),
+                endpoint: endpoint.into_owned()
+            },
+            RequestTarget::Authority(authority) => RequestTarget::Authority(authority.into_owned()),
+            RequestTarget::Asterisk => RequestTarget::Asterisk
+        }
+    }
+}
+
+impl<'a> Default for RequestTarget<'a> {
+    fn default() -> Self {
+        RequestTarget::Origin(Endpoint::default())
+    }
+}
+
+impl<'a> Display for RequestTarget<'a> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            RequestTarget::Origin(endpoint) => write!(f, "{}", endpoint),
+            RequestTarget::Absolute { scheme, authority, endpoint } => write!(f, "{}://{}{}", scheme, authority, endpoint),
+            RequestTarget::Authority(authority) => write!(f, "{}", authority),
+            RequestTarget::Asterisk => write!(f, "*")
+        }
+    }
+}
+
+/// A host, with a port if one was given.
+///
+/// The host is kept as sent: a name, an IPv4 address, or an IPv6 address in
+/// brackets like `[::1]`.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Authority<'a> {
+    pub host: Cow<'a, str>,
+    pub port: Option<u16>
+}
+
+impl<'a> Authority<'a> {
+    pub fn into_owned(self) -> Authority<'static> {
+        Authority { host: Cow::Owned(self.host.into_owned()), port: self.port }
+    }
+}
+
+impl<'a> Display for Authority<'a> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self.port {
+            Some(port) => write!(f, "{}:{}", self.host, port),
+            None => write!(f, "{}", self.host)
+        }
+    }
+}
+
 #[derive(Clone, Debug, PartialEq)]
 pub enum Version<'a> {
     HTTP1_0,
     HTTP1_1,
-    OTHER(&'a str)
+    OTHER(Cow<'a, str>)
+}
+
+impl<'a> Version<'a> {
+    pub fn into_owned(self) -> Version<'static> {
+        match self {
+            Version::HTTP1_0 => Version::HTTP1_0,
+            Version::HTTP1_1 => Version::HTTP1_1,
+            Version::OTHER(protocol) => Version::OTHER(Cow::Owned(protocol.into_owned()))
+        }
+    }
 }
 
 impl<'a> Default for Version<'a> {
     fn default() -> Self {
-        Version::OTHER(&"")
+        Version::OTHER(Cow::Borrowed(""))
     }
 }
 
 impl<'a> Display for Version<'a> {
     fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
         match self {
-            Version::HTTP1_0 => write!(f, "HTTP 1.0"),
-            Version::HTTP1_1 => write!(f, "HTTP 1.1"),
+            Version::HTTP1_0 => write!(f, "HTTP/1.0"),
+            Version::HTTP1_1 => write!(f, "HTTP/1.1"),
             Version::OTHER(protocol) => write!(f, "{}", protocol)
         }
     }
 }
 
 pub type Header<'a> = Field<'a>;
+
+////////////////////////////////////////////////////////////////////////////////////////////////////
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn query_strings_keep_every_parameter() {
+        let query = QueryString::decode("tags=x&flag&name=Ada+Lovelace&&tags=y%2Bz&empty=&limit=10").unwrap();
+        assert_eq!(query.len(), 6, "The empty pair was not skipped!");
+        assert_eq!(query.get("tags"), Some(&b"x"[..]));
+        assert_eq!(query.get_all("tags").collect::<Vec<_>>(), vec![&b"x"[..], b"y+z"], "A repeated key lost a value!");
+        assert_eq!(query.get("name"), Some(&b"Ada Lovelace"[..]), "A + was not decoded as a space!");
+        assert!(query.contains("flag") && query.contains("empty"));
+        assert_eq!(query.get("flag"), Some(&b""[..]));
+        assert_eq!(query.get("missing"), None);
+        assert_eq!(query.parse::<u32>("limit"), Ok(Some(10)));
+        assert_eq!(query.parse::<u32>("missing"), Ok(None));
+        assert!(query.parse::<u32>("name").is_err());
+        let names: Vec<&str> = query.iter().map(Field::name).collect();
+        assert_eq!(names, vec!["tags", "flag", "name", "tags", "empty", "limit"], "The order was not kept!");
+        assert!(matches!(query.iter().next().unwrap().value, Cow::Borrowed(_)), "A plain value was copied!");
+
+        assert_eq!(QueryString::decode("a=1&b=%zz"), Err(ParseError::InvalidTarget { offset: 6 }));
+        assert_eq!(QueryString::decode("%ff=1"), Err(ParseError::InvalidTarget { offset: 0 }));
+        assert!(QueryString::decode("").unwrap().is_empty());
+    }
+}
@@ -1,54 +1,122 @@
+use std::borrow::Cow;
 use std::fmt::{Display, Formatter};
-use crate::http::{Method, Endpoint, Version, Header};
+use crate::http::{Method, Endpoint, RequestTarget, Version, Header};
 
+pub mod chunked;
 pub mod parser;
+pub mod reader;
 
-#[derive(Clone, Debug, Default)]
+/// A parsed request.
+///
+/// A request parsed by the server borrows from its read buffer. Call
+/// `into_owned` to keep it beyond that, for example to hand it to another
+/// thread.
+#[derive(Clone, Debug, Default, PartialEq)]
 pub struct Request<'a> {
     pub method: Method<'a>,
-    pub endpoint: Endpoint<'a>,
+    pub target: RequestTarget<'a>,
     pub version: Version<'a>,
     pub headers: Vec<Header<'a>>,
-    pub body: &'a str
+    pub body: Cow<'a, [u8]>,
+    /// Headers sent after a chunked body.
+    pub trailers: Vec<Header<'a>>
+}
+
+impl<'a> Request<'a> {
+    /// The path and query the request is for, unless it is a `CONNECT` or
+    /// `OPTIONS *` request.
+    pub fn endpoint(&self) -> Option<&Endpoint<'a>> {
+        self.target.endpoint()
+    }
+
+    /// Returns the value of the first header called `name`, ignoring case.
+    pub fn header(&self, name: &str) -> Option<&[u8]> {
+        self.headers.iter()
+            .find(|field| field.name.eq_ignore_ascii_case(name))
+            .map(Header::value)
+    }
+
+    /// The body as text, if it is valid UTF-8.
+    pub fn body_str(&self) -> Result<&str, std::str::Utf8Error> {
+        std::str::from_utf8(&self.body)
+    }
+
+    /// Copies whatever the request borrows, so it no longer depends on the
+    /// buffer it was parsed from.
+    pub fn into_owned(self) -> Request<'static> {
+        Request {
+            method: self.method.into_owned(),
+            target: self.target.into_owned(),
+            version: self.version.into_owned(),
+            headers: self.headers.into_iter().map(Header::into_owned).collect(),
+            body: Cow::Owned(self.body.into_owned()),
+            trailers: self.trailers.into_iter().map(Header::into_owned).collect()
+        }
+    }
+
+    /// Whether the client wants the connection kept open after this request.
+    ///
+    /// HTTP/1.1 connections are persistent unless the client sends
+    /// `Connection: close`; HTTP/1.0 connections only persist when the client
+    /// asks for it with `Connection: keep-alive`.
+    pub fn keep_alive(&self) -> bool {
+        let connection = |token: &[u8]| {
+            self.header("Connection").is_some_and(|value| {
+                value.split(|&byte| byte == b',').any(|option| option.trim_ascii().eq_ignore_ascii_case(token))
+            })
+        };
+        match self.version {
+            Version::HTTP1_1 => !connection(b"close"),
+            Version::HTTP1_0 => connection(b"keep-alive"),
+            Version::OTHER(_) => false
+        }
+    }
+}
+
+impl<'a> TryFrom<&'a [u8]> for Request<'a> {
+    type Error = parser::ParseError;
+
+    fn try_from(value: &'a [u8]) -> Result<Self, Self::Error> {
+        parser::parse_request(value)
+    }
 }
 
 impl<'a> TryFrom<&'a str> for Request<'a> {
-    type Error = std::io::Error;
+    type Error = parser::ParseError;
 
     fn try_from(value: &'a str) -> Result<Self, Self::Error> {
-        Ok(parser::parse_http_request(value)
-            .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e.to_string()))?
-            .1)
+        Request::try_from(value.as_bytes())
     }
 }
 
 impl<'a> Display for Request<'a> {
     fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
         let mut params = vec![];
-        for field in &self.endpoint.parameters {
-            let param = field.name.to_string() + ": " + field.value;
-            params.push(param);
-        }
-        let fragment;
-        match self.endpoint.fragment {
-            Some(f) => fragment = format!("#{}", f),
-            None => fragment = "".parse().unwrap()
+        let mut fragment = String::new();
+        if let Some(endpoint) = self.target.endpoint() {
+            for field in endpoint.query.iter() {
+                let param = field.to_string();
+                params.push(param);
+            }
+            if let Some(f) = &endpoint.fragment {
+                fragment = format!("#{}", f);
+            }
         }
         let mut headers = vec![];
         for field in &self.headers {
-            let header = field.name.to_string() + ": " + field.value;
+            let header = field.to_string();
             headers.push(header);
         }
         write!(f,
                "\
                Request Method: {}\r\n  \
-               Request Path: /{}\r\n    \
+               Request Target: {}\r\n    \
                Parameters: {}\r\n      \
                Fragment: {}\r\n  \
                HTTP version: {}\r\n  \
                HTTP Headers: {}",
                self.method,
-               self.endpoint.segments.join("/"),
+               self.target,
                params.join("\n                "),
                fragment,
                self.version,
@@ -62,7 +130,8 @@ impl<'a> Display for Request<'a> {
 #[cfg(test)]
 mod tests {
     use super::*;
-    use super::super::{RequestParameter};
+    use super::super::{QueryString, RequestParameter};
+    use tracing_test::traced_test;
 
     #[test]
     #[traced_test]
@@ -71,46 +140,56 @@ mod tests {
             "GET /path/to/entrypoint?hello=world&foo=bar#fragment HTTP/1.1\r\n\
             User-Agent: curl7.16.3 libcurl/7.16.3 OpenSSL/0.9.7l zlib/1.2.3\r\n\
             Host: www.example.com\r\n\
-            Accept-Language: en\r\n
+            Accept-Language: en\r\n\
             \r\n\
             ";
-        match Request::try_from(input) {
-            Ok(request) => {
-                println!("\n{:?}", request);
-                assert_eq!(
-                    request.method, Method::GET,
-                    "The two methods we're comparing are not the same!"
-                );
-                assert_eq!(
-                    request.endpoint.segments, vec!["path", "to", "entrypoint"],
-                    "The two segment vectors we're comparing are not the same!"
-                );
-                assert_eq!(
-                    request.endpoint.parameters, vec![
-                        RequestParameter { name: "hello", value: "world" },
-                        RequestParameter { name: "foo", value: "bar" }
-                    ],
-                    "The two parameter vectors we're comparing are not the same!"
-                );
-                assert_eq!(
-                    request.endpoint.fragment, Option::from("fragment"),
-                    "The two fragments we're comparing are not the same!"
-                );
-                assert_eq!(
-                    request.version, Version::HTTP1_1,
-                    "The two protocols we're comparing are not the same!"
-                );
-                assert_eq!(
-                    request.headers, vec![
-                        Header { name: "User-Agent", value: "curl7.16.3 libcurl/7.16.3 OpenSSL/0.9.7l zlib/1.2.3" },
-                        Header { name: "Host", value: "www.example.com" },
-                        Header { name: "Accept-Language", value: "en" }
-                    ],
-                    "The two header vectors we're comparing are not the same!"
-                );
-                println!("{}", request);
-            }
-            _ => {}
-        }
+        let request = Request::try_from(input).unwrap();
+        println!("\n{:?}", request);
+        assert_eq!(
+            request.method, Method::GET,
+            "The two methods we're comparing are not the same!"
+        );
+        assert_eq!(
+            request.endpoint().unwrap().segments, vec!["path", "to", "entrypoint"],
+            "The two segment vectors we're comparing are not the same!"
+        );
+        assert_eq!(
+            request.endpoint().unwrap().query, QueryString::from(vec![
+                RequestParameter::new("hello", b"world"),
+                RequestParameter::new("foo", b"bar")
+            ]),
+            "The two parameter vectors we're comparing are not the same!"
+        );
+        assert_eq!(
+            request.endpoint().unwrap().fragment, Some("fragment".into()),
+            "The two fragments we're comparing are not the same!"
+        );
+        assert_eq!(
+            request.version, Version::HTTP1_1,
+            "The two protocols we're comparing are not the same!"
+        );
+        assert_eq!(
+            request.headers, vec![
+                Header::new("User-Agent", b"curl7.16.3 libcurl/7.16.3 OpenSSL/0.9.7l zlib/1.2.3"),
+                Header::new("Host", b"www.example.com"),
+                Header::new("Accept-Language", b"en")
+            ],
+            "The two header vectors we're comparing are not the same!"
+        );
+        println!("{}", request);
+    }
+
+    #[test]
+    fn into_owned_outlives_the_input() {
+        let input = String::from("POST /upload HTTP/1.1\r\nHost: example.com\r\n\r\n");
+        let mut request = Request::try_from(input.as_str()).unwrap();
+        request.body = b"hello".into();
+        let owned = request.clone().into_owned();
+        drop(input);
+        let owned = std::thread::spawn(move || owned).join().unwrap();
+        assert_eq!(owned.method, Method::POST);
+        assert_eq!(owned.endpoint().unwrap().segments, vec!["upload"]);
+        assert_eq!(owned.header("host"), Some(&b"example.com"[..]));
+        assert_eq!(owned.body_str(), Ok("hello"), "The owned request lost its body!");
     }
 }
@@ -0,0 +1,179 @@
+use crate::http::request::parser::ParseError;
+use crate::http::request::reader::ReadError;
+use tracing::trace;
+
+/// How long a chunk-size line may be, chunk extensions included.
+const MAX_LINE_LENGTH: usize = 4096;
+
+/// Checks whether `input` starts with a complete chunked body, trailers and
+/// final blank line included.
+///
+/// Returns the number of bytes the encoded body takes up, or `None` if more
+/// input is needed to tell. Errors give offsets into `input`. Nothing is
+/// decoded; `decode` does that once the whole body is known to be there.
+pub fn scan(input: &[u8], max_body_size: usize, max_trailer_size: usize) -> Result<Option<usize>, ReadError> {
+    Scanner::new(max_body_size, max_trailer_size).scan(input)
+}
+
+/// Scans a chunked body that arrives in pieces, keeping what it has accepted
+/// between calls, so that each chunk and trailer line is only checked once.
+#[derive(Clone, Debug)]
+pub struct Scanner {
+    max_body_size: usize,
+    max_trailer_size: usize,
+    /// Where the first line not yet accepted starts.
+    position: usize,
+    body_size: usize,
+    /// Where the trailers start, once the last chunk has been seen.
+    trailers_start: Option<usize>
+}
+
+impl Scanner {
+    pub fn new(max_body_size: usize, max_trailer_size: usize) -> Scanner {
+        Scanner { max_body_size, max_trailer_size, position: 0, body_size: 0, trailers_start: None }
+    }
+
+    /// Does what `scan` does, for an `input` that starts with whatever input
+    /// the scanner was given before.
+    pub fn scan(&mut self, input: &[u8]) -> Result<Option<usize>, ReadError> {
+        trace!("Entering scan");
+        while self.trailers_start.is_none() {
+            let error = ParseError::InvalidChunk { offset: self.position };
+            let Some(line_end) = find_line(input, self.position, MAX_LINE_LENGTH, error.into())? else {
+                return Ok(None);
+            };
+            let size = parse_chunk_size(&input[self.position..line_end - 2]).ok_or(error)?;
+            if size == 0 {
+                self.position = line_end;
+                self.trailers_start = Some(line_end);
+                break;
+            }
+            let body_size = self.body_size.checked_add(size)
+                .filter(|&body_size| body_size <= self.max_body_size)
+                .ok_or(ReadError::PayloadTooLarge)?;
+            let data_end = line_end.saturating_add(size);
+            if input.len() < data_end.saturating_add(2) {
+                return Ok(None);
+            }
+            if &input[data_end..data_end + 2] != b"\r\n" {
+                return Err(ParseError::InvalidChunk { offset: data_end }.into());
+            }
+            self.body_size = body_size;
+            self.position = data_end + 2;
+        }
+        let trailers_start = self.trailers_start.unwrap_or(self.position);
+        loop {
+            let remaining = self.max_trailer_size.saturating_sub(self.position - trailers_start);
+            let error = ParseError::HeadersTooLarge { offset: self.position };
+            let Some(line_end) = find_line(input, self.position, remaining, error.into())? else {
+                return Ok(None);
+            };
+            if line_end - self.position == 2 {
+                trace!("Exiting scan ({} bytes, {} decoded)", line_end, self.body_size);
+                return Ok(Some(line_end));
+            }
+            if !input[self.position..line_end].contains(&b':') {
+                return Err(ParseError::InvalidHeader { offset: self.position }.into());
+            }
+            self.position = line_end;
+        }
+    }
+}
+
+/// Decodes a chunked body that `scan` has accepted, in place.
+///
+/// The chunk data is moved to the front of `input`, directly followed by the
+/// trailer lines and the final blank line. Returns the length of the decoded
+/// body and of the trailers.
+pub fn decode(input: &mut [u8]) -> (usize, usize) {
+    let (mut read, mut write) = (0, 0);
+    loop {
+        let line_end = find_line(input, read, input.len(), ParseError::InvalidChunk { offset: read }.into())
+            .ok()
+            .flatten()
+            .unwrap_or(input.len());
+        let size = parse_chunk_size(&input[read..line_end.saturating_sub(2)]).unwrap_or(0);
+        read = line_end;
+        if size == 0 {
+            break;
+        }
+        input.copy_within(read..read + size, write);
+        write += size;
+        read += size + 2;
+    }
+    let trailers = input.len() - read;
+    input.copy_within(read.., write);
+    (write, trailers)
+}
+
+/// Finds the end of the line starting at `from`, just past its `\r\n`.
+///
+/// Fails with `error` if no line ending turns up within `limit` bytes, and
+/// returns `None` if there is not enough input to tell yet.
+fn find_line(input: &[u8], from: usize, limit: usize, error: ReadError) -> Result<Option<usize>, ReadError> {
+    let window = &input[from..input.len().min(from.saturating_add(limit))];
+    match window.windows(2).position(|w| w == b"\r\n") {
+        Some(position) => Ok(Some(from + position + 2)),
+        None if window.len() >= limit => Err(error),
+        None => Ok(None)
+    }
+}
+
+/// Parses a chunk-size line without its `\r\n`, ignoring chunk extensions.
+fn parse_chunk_size(line: &[u8]) -> Option<usize> {
+    let size = match line.iter().position(|&byte| byte == b';') {
+        Some(semicolon) => &line[..semicolon],
+        None => line
+    };
+    let size = size.trim_ascii_end();
+    if size.is_empty() || !size.iter().all(u8::is_ascii_hexdigit) {
+        return None;
+    }
+    std::str::from_utf8(size).ok()
+        .and_then(|size| usize::from_str_radix(size, 16).ok())
+}
+
+////////////////////////////////////////////////////////////////////////////////////////////////////
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tracing_test::traced_test;
+
+    #[test]
+    #[traced_test]
+    fn scan_and_decode_work() {
+        let input =
+            b"5;name=value\r\n\
+            hello\r\n\
+            6\r\n \
+            world\r\n\
+            0\r\n\
+            Expires: never\r\n\
+            \r\n\
+            GET / HTTP/1.1\r\n";
+        let length = input.len() - b"GET / HTTP/1.1\r\n".len();
+        for end in 0..length {
+            assert_eq!(scan(&input[..end], 1024, 1024).unwrap(), None, "Scanned a partial body as complete!");
+        }
+        assert_eq!(scan(input, 1024, 1024).unwrap(), Some(length));
+        let mut scanner = Scanner::new(1024, 1024);
+        for end in 0..length {
+            assert_eq!(scanner.scan(&input[..end]).unwrap(), None, "Resumed scanning a partial body as complete!");
+        }
+        assert_eq!(scanner.scan(input).unwrap(), Some(length), "Resumed scanning lost its place!");
+
+        let mut encoded = input[..length].to_vec();
+        let (body, trailers) = decode(&mut encoded);
+        assert_eq!(&encoded[..body], b"hello world", "The body was not decoded correctly!");
+        assert_eq!(&encoded[body..body + trailers], b"Expires: never\r\n\r\n");
+
+        assert!(matches!(scan(input, 10, 1024), Err(ReadError::PayloadTooLarge)));
+        assert!(matches!(scan(input, 1024, 8), Err(ReadError::Parse(ParseError::HeadersTooLarge { offset: 35 }))));
+        assert!(matches!(scan(b"x\r\n", 1024, 1024), Err(ReadError::Parse(ParseError::InvalidChunk { offset: 0 }))));
+        assert!(matches!(
+            scan(b"5\r\nhello!\r\n", 1024, 1024),
+            Err(ReadError::Parse(ParseError::InvalidChunk { offset: 8 }))
+        ));
+    }
+}
@@ -1,123 +1,395 @@
+use std::borrow::Cow;
+use std::fmt::{Display, Formatter};
 use crate::http::request::Request;
+use crate::http::response::StatusCode;
 use crate::http::{
+    Authority,
     Method,
     Endpoint,
-    RequestParameter,
+    QueryString,
+    RequestTarget,
     Version,
     Header
 };
-use nom::bytes::complete::{take_until, take_while};
+use nom::bytes::complete::take_while;
 use nom::{
     branch::alt,
-    bytes::complete::{tag, take_while1},
-    character::complete::{alpha1, char},
-    combinator::{map, opt, value},
-    multi::{separated_list0},
-    sequence::{preceded, separated_pair},
+    bytes::complete::{tag, take_while1, take_while_m_n},
+    character::complete::{alpha1, char, digit0, digit1, space0},
+    combinator::{map, map_res, opt, recognize, value, verify},
+    multi::many0,
+    sequence::{preceded, separated_pair, terminated, tuple},
     IResult
 };
 use tracing::trace;
 
-pub fn parse_http_request(input: &str) -> IResult<&str, Request> {
+/// Why a request could not be parsed, with the byte offset into the request
+/// at which the problem was found.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum ParseError {
+    /// The method is not a token, or is not followed by a space.
+    InvalidMethod { offset: usize },
+    /// The request target is malformed.
+    InvalidTarget { offset: usize },
+    /// The request line alone did not fit in the head limit.
+    UriTooLong { offset: usize },
+    /// The protocol version is malformed, or the request line does not end
+    /// after it.
+    InvalidVersion { offset: usize },
+    /// The protocol version is well-formed, but not HTTP/1.0 or HTTP/1.1.
+    UnsupportedVersion { offset: usize },
+    /// A header or trailer line is malformed.
+    InvalidHeader { offset: usize },
+    /// The request line fitted in the head limit, but the headers did not.
+    HeadersTooLarge { offset: usize },
+    /// A `Content-Length` is not a valid length, contradicts another one or
+    /// comes alongside a `Transfer-Encoding`.
+    InvalidContentLength { offset: usize },
+    /// A `Transfer-Encoding` names a coding that cannot be decoded.
+    UnsupportedTransferEncoding { offset: usize },
+    /// The chunked body is malformed.
+    InvalidChunk { offset: usize }
+}
+
+impl ParseError {
+    /// Where in the request the problem was found. Offsets into trailers
+    /// count from the start of the trailers.
+    pub fn offset(&self) -> usize {
+        match *self {
+            ParseError::InvalidMethod { offset }
+            | ParseError::InvalidTarget { offset }
+            | ParseError::UriTooLong { offset }
+            | ParseError::InvalidVersion { offset }
+            | ParseError::UnsupportedVersion { offset }
+            | ParseError::InvalidHeader { offset }
+            | ParseError::HeadersTooLarge { offset }
+            | ParseError::InvalidContentLength { offset }
+            | ParseError::UnsupportedTransferEncoding { offset }
+            | ParseError::InvalidChunk { offset } => offset
+        }
+    }
+
+    /// The status code to answer the client with.
+    pub fn status_code(&self) -> StatusCode {
+        match self {
+            ParseError::UriTooLong { .. } => StatusCode::UriTooLong,
+            ParseError::HeadersTooLarge { .. } => StatusCode::RequestHeaderFieldsTooLarge,
+            ParseError::UnsupportedVersion { .. } => StatusCode::HttpVersionNotSupported,
+            ParseError::UnsupportedTransferEncoding { .. } => StatusCode::NotImplemented,
+            _ => StatusCode::BadRequest
+        }
+    }
+
+    /// The same error, found `by` bytes further into the request.
+    pub(crate) fn shifted(self, by: usize) -> ParseError {
+        let offset = self.offset() + by;
+        match self {
+            ParseError::InvalidMethod { .. } => ParseError::InvalidMethod { offset },
+            ParseError::InvalidTarget { .. } => ParseError::InvalidTarget { offset },
+            ParseError::UriTooLong { .. } => ParseError::UriTooLong { offset },
+            ParseError::InvalidVersion { .. } => ParseError::InvalidVersion { offset },
+            ParseError::UnsupportedVersion { .. } => ParseError::UnsupportedVersion { offset },
+            ParseError::InvalidHeader { .. } => ParseError::InvalidHeader { offset },
+            ParseError::HeadersTooLarge { .. } => ParseError::HeadersTooLarge { offset },
+            ParseError::InvalidContentLength { .. } => ParseError::InvalidContentLength { offset },
+            ParseError::UnsupportedTransferEncoding { .. } => ParseError::UnsupportedTransferEncoding { offset },
+            ParseError::InvalidChunk { .. } => ParseError::InvalidChunk { offset }
+        }
+    }
+}
+
+impl Display for ParseError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let problem = match self {
+            ParseError::InvalidMethod { .. } => "invalid method",
+            ParseError::InvalidTarget { .. } => "invalid request target",
+            ParseError::UriTooLong { .. } => "request line is too long",
+            ParseError::InvalidVersion { .. } => "invalid HTTP version",
+            ParseError::UnsupportedVersion { .. } => "unsupported HTTP version",
+            ParseError::InvalidHeader { .. } => "invalid header",
+            ParseError::HeadersTooLarge { .. } => "request headers are too large",
+            ParseError::InvalidContentLength { .. } => "invalid Content-Length",
+            ParseError::UnsupportedTransferEncoding { .. } => "unsupported Transfer-Encoding",
+            ParseError::InvalidChunk { .. } => "invalid chunked body"
+        };
+        write!(f, "{} at byte {}", problem, self.offset())
+    }
+}
+
+impl std::error::Error for ParseError {}
+
+/// Parses a request head, telling which part of it is malformed if it is.
+///
+/// Unlike `parse_http_request`, this only accepts HTTP/1.0 and HTTP/1.1
+/// requests. Whatever follows the head is taken as the body.
+pub fn parse_request(input: &[u8]) -> Result<Request<'_>, ParseError> {
+    trace!("Entering parse_request");
+    let at = |rest: &[u8]| input.len() - rest.len();
+    let failed = |e: nom::Err<nom::error::Error<&[u8]>>| match e {
+        nom::Err::Error(e) | nom::Err::Failure(e) => at(e.input),
+        nom::Err::Incomplete(_) => input.len()
+    };
+    let (rest, method) = terminated(parse_http_method, char(' '))(input)
+        .map_err(|e| ParseError::InvalidMethod { offset: failed(e) })?;
+    let target_offset = at(rest);
+    let (rest, target) = terminated(parse_http_target, char(' '))(rest)
+        .map_err(|e| ParseError::InvalidTarget { offset: failed(e) })?;
+    // Tunnels name a host and port, and only tunnels do; only OPTIONS can be
+    // for the whole server.
+    let fits = match &target {
+        RequestTarget::Authority(_) => method == Method::CONNECT,
+        RequestTarget::Asterisk => method == Method::OPTIONS,
+        RequestTarget::Origin(_) | RequestTarget::Absolute { .. } => method != Method::CONNECT
+    };
+    if !fits {
+        return Err(ParseError::InvalidTarget { offset: target_offset });
+    }
+    let version_offset = at(rest);
+    let (rest, version) = terminated(parse_http_version, tag("\r\n"))(rest)
+        .map_err(|e| ParseError::InvalidVersion { offset: failed(e) })?;
+    // Only a well-formed version this server does not speak is unsupported;
+    // any other word in its place is not a version at all.
+    if let Version::OTHER(protocol) = &version {
+        return Err(if protocol.starts_with("HTTP/") {
+            ParseError::UnsupportedVersion { offset: version_offset }
+        } else {
+            ParseError::InvalidVersion { offset: version_offset }
+        });
+    }
+    let (body, headers) = parse_http_headers(rest)
+        .map_err(|e| ParseError::InvalidHeader { offset: failed(e) })?;
+    trace!("Exiting parse_request");
+    Ok(Request { method, target, version, headers, body: body.into(), trailers: vec![] })
+}
+
+pub fn parse_http_request(input: &[u8]) -> IResult<&[u8], Request<'_>> {
     trace!("Entering parse_http_request");
-    let (input, (method, endpoint, version)) = parse_http_request_line(input)?;
+    let (input, (method, target, version)) = parse_http_request_line(input)?;
     let (body, headers) = parse_http_headers(input)?;
-    trace!("Exiting parse_http_request ({:?}, {:?}, {:?}, {:?}, {:?})", method, endpoint, version, headers, body);
-    Ok((input, Request { method, endpoint, version, headers, body }))
+    trace!("Exiting parse_http_request ({:?}, {:?}, {:?}, {:?}, {:?})", method, target, version, headers, body);
+    Ok((input, Request { method, target, version, headers, body: body.into(), trailers: vec![] }))
 }
 
-pub fn parse_http_request_line(input: &str) -> IResult<&str, (Method, Endpoint, Version)> {
+pub fn parse_http_request_line(input: &[u8]) -> IResult<&[u8], (Method<'_>, RequestTarget<'_>, Version<'_>)> {
     trace!("Entering parse_http_request_line");
     let (input, method) = parse_http_method(input)?;
     let (input, _) = char(' ')(input)?;
-    let (input, endpoint) = parse_http_endpoint(input)?;
+    let (input, target) = parse_http_target(input)?;
     let (input, _) = char(' ')(input)?;
     let (input, version) = parse_http_version(input)?;
     let (input, _) = tag("\r\n")(input)?;
-    trace!("Exiting parse_http_request_line ({:?}, {:?}, {:?})", method, endpoint, version);
-    Ok((input, (method, endpoint, version)))
+    trace!("Exiting parse_http_request_line ({:?}, {:?}, {:?})", method, target, version);
+    Ok((input, (method, target, version)))
 }
 
-pub fn parse_http_method(input: &str) -> IResult<&str, Method> {
+/// Parses a method, which is any token; the standard methods are only told
+/// apart once the whole token has been read, so `GETX` is not `GET`.
+pub fn parse_http_method(input: &[u8]) -> IResult<&[u8], Method<'_>> {
     trace!("Entering parse_http_method");
-    let (input, method) = alt((
-        value(Method::GET, tag("GET")),
-        value(Method::HEAD, tag("HEAD")),
-        value(Method::POST, tag("POST")),
-        value(Method::PUT, tag("PUT")),
-        value(Method::DELETE, tag("DELETE")),
-        value(Method::CONNECT, tag("CONNECT")),
-        value(Method::OPTIONS, tag("OPTIONS")),
-        value(Method::TRACE, tag("TRACE")),
-        value(Method::PATCH, tag("PATCH")),
-        map(alpha1, |s| Method::OTHER(s)),
-    ))(input)?;
+    let (input, method) = map(text(take_while1(token_character)), |method| match method {
+        "GET" => Method::GET,
+        "HEAD" => Method::HEAD,
+        "POST" => Method::POST,
+        "PUT" => Method::PUT,
+        "DELETE" => Method::DELETE,
+        "CONNECT" => Method::CONNECT,
+        "OPTIONS" => Method::OPTIONS,
+        "TRACE" => Method::TRACE,
+        "PATCH" => Method::PATCH,
+        method => Method::OTHER(method.into()),
+    })(input)?;
     trace!("Exiting parse_http_method ({:?})", method);
     Ok((input, method))
 }
 
-pub fn parse_http_endpoint(input: &str) -> IResult<&str, Endpoint> {
+/// Parses a request target in any of its four forms.
+pub fn parse_http_target(input: &[u8]) -> IResult<&[u8], RequestTarget<'_>> {
+    trace!("Entering parse_http_target");
+    // Told apart by their first byte where possible, so errors point into
+    // the form that was meant.
+    let (input, target) = match input.first() {
+        Some(b'*') => value(RequestTarget::Asterisk, char('*'))(input)?,
+        Some(b'/') => map(parse_http_endpoint, RequestTarget::Origin)(input)?,
+        _ => alt((
+            parse_http_absolute_target,
+            map(verify(parse_http_authority, |authority| authority.port.is_some()), RequestTarget::Authority),
+        ))(input)?
+    };
+    trace!("Exiting parse_http_target ({:?})", target);
+    Ok((input, target))
+}
+
+/// Parses a request target in absolute form, a URI like
+/// `http://example.com:8080/index.html?page=2`. A URI without a path gets
+/// the same segments as `/`.
+pub fn parse_http_absolute_target(input: &[u8]) -> IResult<&[u8], RequestTarget<'_>> {
+    trace!("Entering parse_http_absolute_target");
+    let (rest, scheme) = text(recognize(preceded(
+        take_while_m_n(1, 1, |ch: u8| ch.is_ascii_alphabetic()),
+        take_while(|ch: u8| ch.is_ascii_alphanumeric() || b"+-.".contains(&ch))
+    )))(input)?;
+    let (rest, authority) = preceded(tag("://"), parse_http_authority)(rest)?;
+    let (rest, raw_path) = text(recognize(opt(preceded(char('/'), take_while(path_character)))))(rest)?;
+    // An empty path stands for `/` (RFC 9112 section 3.2.2).
+    let raw_path = if raw_path.is_empty() { "/" } else { raw_path };
+    let (rest, endpoint) = parse_query_after(input, raw_path, rest)?;
+    trace!("Exiting parse_http_absolute_target ({:?}, {:?}, {:?})", scheme, authority, endpoint);
+    Ok((rest, RequestTarget::Absolute { scheme: scheme.into(), authority, endpoint }))
+}
+
+/// Parses a host with an optional port, like `example.com:443` or
+/// `[::1]:8080`. User information, which HTTP does not allow, is not taken.
+pub fn parse_http_authority(input: &[u8]) -> IResult<&[u8], Authority<'_>> {
+    trace!("Entering parse_http_authority");
+    let (input, host) = text(alt((
+        recognize(tuple((char('['), take_while1(|ch: u8| ch.is_ascii_hexdigit() || ch == b':' || ch == b'.'), char(']')))),
+        take_while1(|ch: u8| ch.is_ascii_alphanumeric() || b"-._~!$&'()*+,;=%".contains(&ch)),
+    )))(input)?;
+    let (input, port) = opt(preceded(char(':'), map_res(text(digit0), |port: &str| match port {
+        "" => Ok(None),
+        port => port.parse::<u16>().map(Some)
+    })))(input)?;
+    trace!("Exiting parse_http_authority ({:?}, {:?})", host, port);
+    Ok((input, Authority { host: host.into(), port: port.flatten() }))
+}
+
+/// Parses a request target in origin form (RFC 3986): an absolute path,
+/// optionally followed by a query and, though clients should not send one,
+/// a fragment.
+///
+/// The path segments and query parameters are decoded, borrowing the
+/// input where nothing needed decoding. A segment that does not decode to
+/// UTF-8 is rejected, since routes match on text.
+pub fn parse_http_endpoint(input: &[u8]) -> IResult<&[u8], Endpoint<'_>> {
     trace!("Entering parse_http_endpoint");
-    let (input, _) = char('/')(input)?;
-    let (input, segments) = separated_list0(char('/'), take_while(valid_character))(input)?;
-    let (input, parameters) = map(opt(parse_http_request_parameters),|a| a.unwrap_or(Vec::default()))(input)?;
-    let (input, fragment) = opt(preceded(char('#'), alpha1))(input)?;
-    trace!("Exiting parse_http_endpoint ({:?}, {:?}, {:?})", segments, parameters, fragment);
-    Ok((input, Endpoint { segments, parameters, fragment }))
+    let (rest, raw_path) = text(recognize(preceded(char('/'), take_while(path_character))))(input)?;
+    let (rest, endpoint) = parse_query_after(input, raw_path, rest)?;
+    trace!("Exiting parse_http_endpoint ({:?})", endpoint);
+    Ok((rest, endpoint))
 }
 
-pub fn parse_http_request_parameters(input: &str) -> IResult<&str, Vec<RequestParameter>> {
-    trace!("Entering parse_http_request_parameters");
-    let (input, _) = char('?')(input)?;
-    let (input, parameters) = separated_list0(char('&'), parse_http_request_parameter)(input)?;
-    trace!("Exiting parse_http_request_parameters ({:?})", parameters);
-    Ok((input, parameters))
+/// Parses the query and fragment following `raw_path`, which was taken from
+/// `input`, and decodes both path and query.
+fn parse_query_after<'a>(input: &'a [u8], raw_path: &'a str, rest: &'a [u8]) -> IResult<&'a [u8], Endpoint<'a>> {
+    let (rest, raw_query) = opt(preceded(char('?'), text(take_while(query_character))))(rest)?;
+    let (rest, fragment) = opt(preceded(char('#'), text(take_while(query_character))))(rest)?;
+    // Where in `input` a decoding error at `offset` into `part` is.
+    let failed = |part: &str, offset: usize| {
+        let at = part.as_ptr() as usize - input.as_ptr() as usize + offset;
+        nom::Err::Error(nom::error::Error::new(&input[at..], nom::error::ErrorKind::Verify))
+    };
+    let mut segments = vec![];
+    for segment in raw_path.strip_prefix('/').unwrap_or(raw_path).split('/') {
+        segments.push(percent_decode_str(segment).map_err(|offset| failed(segment, offset))?);
+    }
+    let query = match raw_query {
+        Some(raw_query) => QueryString::decode(raw_query).map_err(|e| failed(raw_query, e.offset()))?,
+        None => QueryString::default()
+    };
+    Ok((rest, Endpoint {
+        raw_path: raw_path.into(),
+        raw_query: raw_query.map(Cow::from),
+        segments,
+        query,
+        fragment: fragment.map(Cow::from)
+    }))
 }
 
-pub fn parse_http_request_parameter(input: &str) -> IResult<&str, RequestParameter> {
-    trace!("Entering parse_http_request_parameter");
-    let (input, (name, value)) = separated_pair(
-        take_while1(valid_character),
-        char('='),
-        take_while1(valid_character),
-    )(input)?;
-    trace!( "Exiting parse_http_request_parameter ({:?}, {:?})", name, value );
-    Ok((input, RequestParameter { name, value }))
+/// Decodes the `%XX` escapes in `input`, borrowing it if there are none.
+/// Fails with the offset of the first malformed escape.
+pub fn percent_decode(input: &[u8]) -> Result<Cow<'_, [u8]>, usize> {
+    let Some(first) = input.iter().position(|&byte| byte == b'%') else {
+        return Ok(Cow::Borrowed(input));
+    };
+    let mut decoded = input[..first].to_vec();
+    let mut position = first;
+    while position < input.len() {
+        if input[position] != b'%' {
+            decoded.push(input[position]);
+            position += 1;
+            continue;
+        }
+        let escape = input.get(position + 1..position + 3)
+            .and_then(|hex| std::str::from_utf8(hex).ok())
+            .filter(|hex| hex.bytes().all(|byte| byte.is_ascii_hexdigit()))
+            .and_then(|hex| u8::from_str_radix(hex, 16).ok())
+            .ok_or(position)?;
+        decoded.push(escape);
+        position += 3;
+    }
+    Ok(Cow::Owned(decoded))
+}
+
+/// Decodes the `%XX` escapes in `input` like `percent_decode`, also failing
+/// at offset `0` if the result is not UTF-8.
+pub fn percent_decode_str(input: &str) -> Result<Cow<'_, str>, usize> {
+    match percent_decode(input.as_bytes())? {
+        Cow::Borrowed(_) => Ok(Cow::Borrowed(input)),
+        Cow::Owned(decoded) => String::from_utf8(decoded).map(Cow::Owned).map_err(|_| 0)
+    }
 }
 
-pub fn parse_http_version(input: &str) -> IResult<&str, Version> {
+pub fn parse_http_version(input: &[u8]) -> IResult<&[u8], Version<'_>> {
     trace!("Entering parse_http_version");
     let (input, version) = alt((
         value(Version::HTTP1_0, tag("HTTP/1.0")),
         value(Version::HTTP1_1, tag("HTTP/1.1")),
-        map(alpha1, |s| Version::OTHER(s)),
+        map(text(recognize(tuple((tag("HTTP/"), digit1, char('.'), digit1)))), |protocol| {
+            Version::OTHER(protocol.into())
+        }),
+        map(text(alpha1), |protocol| Version::OTHER(protocol.into())),
     ))(input)?;
     trace!("Exiting parse_http_version");
     Ok((input, version))
 }
 
-fn parse_http_headers(input: &str) -> IResult<&str, Vec<Header>> {
+pub fn parse_http_headers(input: &[u8]) -> IResult<&[u8], Vec<Header<'_>>> {
     trace!("Entering parse_http_headers");
-    let (input, headers) = separated_list0(tag("\r\n"), parse_http_header)(input)?;
+    let (input, headers) = many0(terminated(parse_http_header, tag("\r\n")))(input)?;
+    let (input, _) = tag("\r\n")(input)?;
     trace!("Exiting parse_http_headers");
     Ok((input, headers))
 }
 
-fn parse_http_header(input: &str) -> IResult<&str, Header> {
+fn parse_http_header(input: &[u8]) -> IResult<&[u8], Header<'_>> {
     trace!("Entering parse_http_header");
-    let (input, (name, value)) =
-        separated_pair(take_while1(valid_character), tag(": "), take_until("\r\n"))(input)?;
+    let (input, (name, value)) = separated_pair(
+        text(take_while1(token_character)),
+        preceded(char(':'), space0),
+        map(take_while(field_character), <[u8]>::trim_ascii_end),
+    )(input)?;
     trace!("Exiting parse_http_header ({:?}, {:?})", name, value);
-    Ok((input, Header { name, value }))
+    Ok((input, Header::new(name, value)))
+}
+
+/// Turns a parser of ASCII bytes into one of text.
+fn text<'a, F>(parser: F) -> impl FnMut(&'a [u8]) -> IResult<&'a [u8], &'a str>
+where
+    F: FnMut(&'a [u8]) -> IResult<&'a [u8], &'a [u8]>
+{
+    map_res(parser, std::str::from_utf8)
+}
+
+/// Whether `ch` may appear in an absolute path: a `pchar` of RFC 3986, the
+/// `%` of an escape or a `/`.
+fn path_character(ch: u8) -> bool {
+    ch.is_ascii_alphanumeric() || b"-._~!$&'()*+,;=:@%/".contains(&ch)
+}
+
+/// Whether `ch` may appear in a query or fragment.
+fn query_character(ch: u8) -> bool {
+    path_character(ch) || ch == b'?'
 }
 
-fn valid_character(ch: char) -> bool {
-    ('A' <= ch && ch <= 'Z')
-        || ('a' <= ch && ch <= 'z')
-        || ('0' <= ch && ch <= '9')
-        || ch == '-'
-        || ch == '_'
-        || ch == '.'
+/// Whether `ch` may appear in a header name (a `tchar` in RFC 9110).
+pub(crate) fn token_character(ch: u8) -> bool {
+    ch.is_ascii_alphanumeric() || b"!#$%&'*+-.^_`|~".contains(&ch)
+}
+
+/// Whether `ch` may appear in a header value, including the `obs-text` bytes
+/// above `0x7F` that older clients still send.
+pub(crate) fn field_character(ch: u8) -> bool {
+    ch == b'\t' || (ch >= b' ' && ch != 0x7F)
 }
 
 ////////////////////////////////////////////////////////////////////////////////////////////////////
@@ -125,69 +397,130 @@ fn valid_character(ch: char) -> bool {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::http::RequestParameter;
     use tracing_test::traced_test;
 
     #[test]
     #[traced_test]
     fn parse_http_request_works() {
         let input =
-            "GET /favicon.ico HTTP/1.1\r\n\
-            Host: 127.0.0.1:8080\r\n\
-            Connection: keep-alive\r\n\
-            User-Agent: Mozilla/5.0 (Macintosh; Intel Mac OS X 10_15_7) AppleWebKit/537.36 (KHTML, like Gecko) Chrome/105.0.0.0 Safari/537.36\r\n\
-            Accept: image/avif,image/webp,image/apng,image/svg+xml,image/*,*/*;q=0.8\r\n\
-            Sec-GPC: 1\
-            Accept-Language: da\r\n\
-            Sec-Fetch-Site: same-origin\r\n\
-            Sec-Fetch-Mode: no-cors\r\n\
-            Sec-Fetch-Dest: image\r\n\
-            Referer: http://127.0.0.1:8080/\r\n\
-            Accept-Encoding: gzip, deflate, br\r\n\
+            b"GET /some/service/path?hello=world&foo=bar#fragment HTTP/1.1\r\n\
+            User-Agent: curl7.16.3 libcurl/7.16.3 OpenSSL/0.9.7l zlib/1.2.3\r\n\
+            Host: www.example.com\r\n\
+            Accept-Language: en\r\n\
+            \r\n\
             ";
-            //"GET /some/service/path?hello=world&foo=bar#fragment HTTP/1.1\r\n\
-            //User-Agent: curl7.16.3 libcurl/7.16.3 OpenSSL/0.9.7l zlib/1.2.3\r\n\
-            //Host: www.example.com\r\n\
-            //Accept-Language: en\r\n\
-            //\r\n\
-            //";
-        match parse_http_request(input) {
-            Ok(result) => {
-                let request = result.1;
-                println!("\n{:?}", request);
-                assert_eq!(
-                    request.method, Method::GET,
-                    "The two methods we're comparing are not the same!"
-                );
-                assert_eq!(
-                    request.endpoint.segments, vec!["some", "service", "path"],
-                    "The two segment vectors we're comparing are not the same!"
-                );
-                assert_eq!(
-                    request.endpoint.parameters, vec![
-                        RequestParameter { name: "hello", value: "world" },
-                        RequestParameter { name: "foo", value: "bar" }
-                    ],
-                    "The two parameter vectors we're comparing are not the same!"
-                );
-                assert_eq!(
-                    request.endpoint.fragment, Option::from("fragment"),
-                    "The two fragments we're comparing are not the same!"
-                );
-                assert_eq!(
-                    request.version, Version::HTTP1_1,
-                    "The two protocols we're comparing are not the same!"
-                );
-                assert_eq!(
-                    request.headers, vec![
-                        Header { name: "User-Agent", value: "curl7.16.3 libcurl/7.16.3 OpenSSL/0.9.7l zlib/1.2.3" },
-                        Header { name: "Host", value: "www.example.com" },
-                        Header { name: "Accept-Language", value: "en" }
-                    ],
-                    "The two header vectors we're comparing are not the same!"
-                );
-                println!("\n{}", request);
-            },
-            _ => {}
-        }
+        let request = parse_http_request(input).unwrap().1;
+        println!("\n{:?}", request);
+        assert_eq!(
+            request.method, Method::GET,
+            "The two methods we're comparing are not the same!"
+        );
+        assert_eq!(
+            request.endpoint().unwrap().segments, vec!["some", "service", "path"],
+            "The two segment vectors we're comparing are not the same!"
+        );
+        assert_eq!(
+            request.endpoint().unwrap().query, QueryString::from(vec![
+                RequestParameter::new("hello", b"world"),
+                RequestParameter::new("foo", b"bar")
+            ]),
+            "The two parameter vectors we're comparing are not the same!"
+        );
+        assert_eq!(
+            request.endpoint().unwrap().fragment, Some("fragment".into()),
+            "The two fragments we're comparing are not the same!"
+        );
+        assert_eq!(
+            request.version, Version::HTTP1_1,
+            "The two protocols we're comparing are not the same!"
+        );
+        assert_eq!(
+            request.headers, vec![
+                Header::new("User-Agent", b"curl7.16.3 libcurl/7.16.3 OpenSSL/0.9.7l zlib/1.2.3"),
+                Header::new("Host", b"www.example.com"),
+                Header::new("Accept-Language", b"en")
+            ],
+            "The two header vectors we're comparing are not the same!"
+        );
+        println!("\n{}", request);
+    }
+
+    #[test]
+    fn parse_http_endpoint_decodes_percent_escapes() {
+        let (rest, endpoint) = parse_http_endpoint(b"/files/my%20file~1+2/a:b@c?q=b%26c&tag=%F0%9F%A6%80&flag&&x=%ff HTTP/1.1").unwrap();
+        assert_eq!(rest, b" HTTP/1.1");
+        assert_eq!(endpoint.raw_path, "/files/my%20file~1+2/a:b@c", "The raw path was not kept!");
+        assert_eq!(endpoint.raw_query.as_deref(), Some("q=b%26c&tag=%F0%9F%A6%80&flag&&x=%ff"));
+        assert_eq!(endpoint.segments, vec!["files", "my file~1+2", "a:b@c"], "The segments were not decoded!");
+        assert!(matches!(endpoint.segments[0], Cow::Borrowed(_)), "A segment without escapes was copied!");
+        assert_eq!(endpoint.query, QueryString::from(vec![
+            RequestParameter::new("q", b"b&c"),
+            RequestParameter::new("tag", "🦀".as_bytes()),
+            RequestParameter::new("flag", b""),
+            RequestParameter::new("x", b"\xff")
+        ]));
+
+        assert_eq!(percent_decode(b"100%25").unwrap(), &b"100%"[..]);
+        assert_eq!(percent_decode(b"a%2"), Err(1));
+        assert_eq!(percent_decode(b"%zz"), Err(0));
+        assert!(parse_http_endpoint(b"/%ff HTTP/1.1").is_err(), "A segment that is not UTF-8 was accepted!");
+    }
+
+    #[test]
+    fn parse_http_target_takes_every_form() {
+        let target = |input: &'static str| parse_request(input.as_bytes()).map(|request| request.target);
+        let Ok(RequestTarget::Absolute { scheme, authority, endpoint }) = target("GET http://example.com:8080/a%20b?x=1 HTTP/1.1\r\n\r\n") else {
+            panic!("The absolute form was not parsed!");
+        };
+        assert_eq!(scheme, "http");
+        assert_eq!(authority, Authority { host: "example.com".into(), port: Some(8080) });
+        assert_eq!(endpoint.segments, vec!["a b"]);
+        assert_eq!(endpoint.raw_query.as_deref(), Some("x=1"));
+        let Ok(RequestTarget::Absolute { authority, endpoint, .. }) = target("GET https://[::1] HTTP/1.1\r\n\r\n") else {
+            panic!("The absolute form without a path was not parsed!");
+        };
+        assert_eq!(authority, Authority { host: "[::1]".into(), port: None });
+        assert_eq!(endpoint.segments, vec![""], "A URI without a path was not taken as /!");
+        assert_eq!(endpoint.raw_path, "/", "A URI without a path kept an empty path!");
+        let Ok(RequestTarget::Absolute { endpoint, .. }) = target("GET http://example.com?x=1 HTTP/1.1\r\n\r\n") else {
+            panic!("The absolute form with a query but no path was not parsed!");
+        };
+        assert_eq!((endpoint.raw_path.as_ref(), endpoint.raw_query.as_deref()), ("/", Some("x=1")));
+        assert_eq!(
+            target("CONNECT example.com:443 HTTP/1.1\r\n\r\n"),
+            Ok(RequestTarget::Authority(Authority { host: "example.com".into(), port: Some(443) }))
+        );
+        assert_eq!(target("OPTIONS * HTTP/1.1\r\n\r\n"), Ok(RequestTarget::Asterisk));
+
+        assert!(target("CONNECT example.com HTTP/1.1\r\n\r\n").is_err(), "A tunnel without a port was accepted!");
+        assert!(target("CONNECT /path HTTP/1.1\r\n\r\n").is_err());
+        assert!(target("GET example.com:443 HTTP/1.1\r\n\r\n").is_err());
+        assert!(target("GET * HTTP/1.1\r\n\r\n").is_err());
+        assert!(target("GET http://user@example.com/ HTTP/1.1\r\n\r\n").is_err(), "User information was accepted!");
+        assert!(target("CONNECT example.com:65536 HTTP/1.1\r\n\r\n").is_err());
+        assert_eq!(
+            parse_request(b"GET http://example.com:8080/a?x=1 HTTP/1.1\r\n\r\n").unwrap().target.to_string(),
+            "http://example.com:8080/a?x=1"
+        );
+    }
+
+    #[test]
+    fn parse_request_reports_where_it_failed() {
+        let parse = |input: &str| parse_request(input.as_bytes()).map(|_| ());
+        assert_eq!(parse("GET /path HTTP/1.1\r\nHost: a\r\n\r\n"), Ok(()));
+        assert_eq!(parse("G@T / HTTP/1.1\r\n\r\n"), Err(ParseError::InvalidMethod { offset: 1 }));
+        let method = |input: &str| parse_request(input.as_bytes()).map(|request| request.method.to_string());
+        assert_eq!(method("GETX / HTTP/1.1\r\n\r\n"), Ok("GETX".to_string()), "GETX was taken for GET!");
+        assert_eq!(method("M-SEARCH / HTTP/1.1\r\n\r\n"), Ok("M-SEARCH".to_string()));
+        assert_eq!(parse("GET /pa%th HTTP/1.1\r\n\r\n"), Err(ParseError::InvalidTarget { offset: 7 }));
+        assert_eq!(parse("GET / HTTP/1.x\r\n\r\n"), Err(ParseError::InvalidVersion { offset: 10 }));
+        assert_eq!(parse("GET / HTTP/2.0\r\n\r\n"), Err(ParseError::UnsupportedVersion { offset: 6 }));
+        assert_eq!(parse("GET / FOO\r\n\r\n"), Err(ParseError::InvalidVersion { offset: 6 }));
+        assert_eq!(parse("GET / HTTP/1.1\r\nHost: a\r\nBad Header\r\n\r\n"), Err(ParseError::InvalidHeader { offset: 25 }));
+        assert_eq!(parse("GET / FOO\r\n\r\n").unwrap_err().status_code(), StatusCode::BadRequest);
+        let error = ParseError::UnsupportedVersion { offset: 6 };
+        assert_eq!(error.status_code(), StatusCode::HttpVersionNotSupported);
+        assert_eq!(error.to_string(), "unsupported HTTP version at byte 6");
     }
 }
@@ -0,0 +1,454 @@
+use std::fmt::{Display, Formatter};
+use std::io::{ErrorKind, Read};
+use crate::http::request::chunked;
+use crate::http::request::parser::ParseError;
+use crate::http::response::StatusCode;
+use tracing::trace;
+
+/// How many bytes are requested from the underlying stream per read.
+const CHUNK_SIZE: usize = 4096;
+
+/// Reads requests off a stream, one at a time.
+///
+/// Bytes are buffered until the blank line ending the request head has been
+/// seen, however many reads that takes, and then until the whole body framed
+/// by `Content-Length` or `Transfer-Encoding: chunked` has arrived. Anything
+/// read past the end of the current request is kept for the next one, so
+/// pipelined requests survive.
+pub struct RequestReader<R> {
+    inner: R,
+    buffer: RequestBuffer
+}
+
+/// The buffering and framing behind `RequestReader`, for callers that read
+/// from the stream themselves, such as non-blocking ones.
+///
+/// Bytes are added with `extend` or `read_from` until `has_request` reports a
+/// complete request, which `take_request` then hands out.
+pub struct RequestBuffer {
+    buffer: Vec<u8>,
+    consumed: usize,
+    ready: Option<(usize, Framing, usize)>,
+    /// How much of the buffer has been searched for the end of the head, and
+    /// the head once found, so that a request arriving in pieces is not
+    /// scanned from its start again on every read.
+    searched: usize,
+    framed: Option<(usize, Framing, chunked::Scanner)>,
+    limit: usize,
+    max_body_size: usize,
+    require_length: bool
+}
+
+#[derive(Debug)]
+pub enum ReadError {
+    /// The underlying stream failed, timed out or was closed.
+    Io(std::io::Error),
+    /// The request is malformed, or its head too large.
+    Parse(ParseError),
+    /// The request has a method that implies a body, but no declared length.
+    LengthRequired,
+    /// The request body is longer than the body limit.
+    PayloadTooLarge
+}
+
+impl ReadError {
+    /// The status code to answer the client with, if it should be answered.
+    pub fn status_code(&self) -> Option<StatusCode> {
+        match self {
+            ReadError::Io(_) => None,
+            ReadError::Parse(e) => Some(e.status_code()),
+            ReadError::LengthRequired => Some(StatusCode::LengthRequired),
+            ReadError::PayloadTooLarge => Some(StatusCode::PayloadTooLarge)
+        }
+    }
+}
+
+impl Display for ReadError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ReadError::Io(e) => write!(f, "{}", e),
+            ReadError::Parse(e) => write!(f, "{}", e),
+            ReadError::LengthRequired => write!(f, "request body without Content-Length"),
+            ReadError::PayloadTooLarge => write!(f, "request body is too large")
+        }
+    }
+}
+
+impl std::error::Error for ReadError {}
+
+impl From<std::io::Error> for ReadError {
+    fn from(e: std::io::Error) -> Self {
+        ReadError::Io(e)
+    }
+}
+
+impl From<ParseError> for ReadError {
+    fn from(e: ParseError) -> Self {
+        ReadError::Parse(e)
+    }
+}
+
+/// The parts of a request read by `RequestReader::read_request`.
+///
+/// A chunked body has already been decoded, and its trailers are the raw
+/// trailer lines followed by the final blank line.
+#[derive(Debug, PartialEq)]
+pub struct RawRequest<'a> {
+    pub head: &'a [u8],
+    pub body: &'a [u8],
+    pub trailers: &'a [u8]
+}
+
+/// How the body following a request head is delimited.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Framing {
+    Length(usize),
+    Chunked
+}
+
+impl<R: Read> RequestReader<R> {
+    /// Creates a reader that gives up on request heads longer than `limit`
+    /// bytes, counting the terminating blank line.
+    pub fn new(inner: R, limit: usize) -> RequestReader<R> {
+        RequestReader { inner, buffer: RequestBuffer::new(limit) }
+    }
+
+    /// Sets how many bytes a request body may take up.
+    pub fn max_body_size(mut self, max_body_size: usize) -> RequestReader<R> {
+        self.buffer = self.buffer.max_body_size(max_body_size);
+        self
+    }
+
+    /// Sets whether `POST`, `PUT` and `PATCH` requests must declare their
+    /// body length up front.
+    pub fn require_length(mut self, require_length: bool) -> RequestReader<R> {
+        self.buffer = self.buffer.require_length(require_length);
+        self
+    }
+
+    /// Whether bytes of a next request have already been read.
+    pub fn is_empty(&self) -> bool {
+        self.buffer.is_empty()
+    }
+
+    /// Reads the next request.
+    ///
+    /// The request handed out by the previous call is discarded first. Only
+    /// the bytes framed by the request's `Content-Length` or chunked encoding
+    /// are taken as its body; a request with neither has an empty body. A
+    /// stream that ends between requests is reported as an `UnexpectedEof`
+    /// error.
+    pub fn read_request(&mut self) -> Result<RawRequest<'_>, ReadError> {
+        trace!("Entering read_request");
+        loop {
+            if self.buffer.has_request()? {
+                return Ok(self.buffer.take_request().unwrap());
+            }
+            if self.buffer.read_from(&mut self.inner)? == 0 {
+                return Err(self.buffer.closed());
+            }
+        }
+    }
+}
+
+impl RequestBuffer {
+    /// Creates a buffer that gives up on request heads longer than `limit`
+    /// bytes, counting the terminating blank line.
+    pub fn new(limit: usize) -> RequestBuffer {
+        RequestBuffer {
+            buffer: Vec::new(),
+            consumed: 0,
+            ready: None,
+            searched: 0,
+            framed: None,
+            limit,
+            max_body_size: usize::MAX,
+            require_length: false
+        }
+    }
+
+    /// Sets how many bytes a request body may take up.
+    pub fn max_body_size(mut self, max_body_size: usize) -> RequestBuffer {
+        self.max_body_size = max_body_size;
+        self
+    }
+
+    /// Sets whether `POST`, `PUT` and `PATCH` requests must declare their
+    /// body length up front.
+    pub fn require_length(mut self, require_length: bool) -> RequestBuffer {
+        self.require_length = require_length;
+        self
+    }
+
+    /// Whether nothing past the request last handed out has been buffered.
+    pub fn is_empty(&self) -> bool {
+        self.buffer.len() == self.consumed
+    }
+
+    /// Adds bytes read from the stream.
+    pub fn extend(&mut self, bytes: &[u8]) {
+        self.discard_consumed();
+        self.buffer.extend_from_slice(bytes);
+    }
+
+    /// Reads once from `reader` into the buffer, returning the number of bytes
+    /// added.
+    pub fn read_from<R: Read>(&mut self, reader: &mut R) -> std::io::Result<usize> {
+        self.discard_consumed();
+        let start = self.buffer.len();
+        self.buffer.resize(start + CHUNK_SIZE, 0);
+        loop {
+            match reader.read(&mut self.buffer[start..]) {
+                Ok(size) => {
+                    self.buffer.truncate(start + size);
+                    return Ok(size);
+                }
+                Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+                Err(e) => {
+                    self.buffer.truncate(start);
+                    return Err(e);
+                }
+            }
+        }
+    }
+
+    /// Whether a complete request has been buffered, failing if what has been
+    /// buffered cannot become one.
+    pub fn has_request(&mut self) -> Result<bool, ReadError> {
+        self.discard_consumed();
+        if self.ready.is_none() {
+            self.ready = self.frame()?;
+        }
+        Ok(self.ready.is_some())
+    }
+
+    /// Hands out the request `has_request` found, discarding the one handed
+    /// out before.
+    pub fn take_request(&mut self) -> Option<RawRequest<'_>> {
+        self.discard_consumed();
+        let (head, framing, end) = self.ready.take()?;
+        self.consumed = end;
+        self.searched = 0;
+        self.framed = None;
+        let (head, rest) = self.buffer[..end].split_at_mut(head);
+        let (body, trailers) = match framing {
+            Framing::Length(_) => (rest.len(), 0),
+            Framing::Chunked => chunked::decode(rest)
+        };
+        trace!("Took request ({} + {} + {} bytes)", head.len(), body, trailers);
+        Some(RawRequest {
+            head,
+            body: &rest[..body],
+            trailers: &rest[body..body + trailers]
+        })
+    }
+
+    /// The error to report when the stream ends before a complete request.
+    pub fn closed(&self) -> ReadError {
+        let message = if self.is_empty() {
+            "connection closed by peer"
+        } else {
+            "connection closed in the middle of a request"
+        };
+        std::io::Error::new(ErrorKind::UnexpectedEof, message).into()
+    }
+
+    fn discard_consumed(&mut self) {
+        if self.consumed > 0 {
+            self.buffer.drain(..self.consumed);
+            self.consumed = 0;
+        }
+    }
+
+    /// Looks for a complete request at the start of the buffer, returning the
+    /// length of its head, how its body is framed and where it ends.
+    fn frame(&mut self) -> Result<Option<(usize, Framing, usize)>, ReadError> {
+        if self.framed.is_none() {
+            let Some(head) = self.find_head()? else {
+                return Ok(None);
+            };
+            let framing = self.framing(&self.buffer[..head])?;
+            self.framed = Some((head, framing, chunked::Scanner::new(self.max_body_size, self.limit)));
+        }
+        let Some((head, framing, chunks)) = &mut self.framed else {
+            return Ok(None);
+        };
+        let head = *head;
+        let end = match *framing {
+            Framing::Length(length) if self.buffer.len() - head >= length => Some(head + length),
+            Framing::Length(_) => None,
+            Framing::Chunked => chunks.scan(&self.buffer[head..])
+                .map_err(|e| match e {
+                    ReadError::Parse(e) => ReadError::Parse(e.shifted(head)),
+                    e => e
+                })?
+                .map(|length| head + length)
+        };
+        Ok(end.map(|end| (head, *framing, end)))
+    }
+
+    /// Looks for the end of the head in what has arrived since the last look.
+    fn find_head(&mut self) -> Result<Option<usize>, ReadError> {
+        let window = &self.buffer[..self.buffer.len().min(self.limit)];
+        // The blank line may have started in the bytes searched before.
+        let from = self.searched.saturating_sub(3);
+        if let Some(end) = find_head_end(&window[from..]) {
+            return Ok(Some(from + end));
+        }
+        if window.len() < self.limit {
+            self.searched = window.len();
+            return Ok(None);
+        }
+        let offset = window.len();
+        Err(if window.windows(2).any(|w| w == b"\r\n") {
+            ParseError::HeadersTooLarge { offset }
+        } else {
+            ParseError::UriTooLong { offset }
+        }.into())
+    }
+
+    /// Works out how the body following `head` is framed from its headers.
+    fn framing(&self, head: &[u8]) -> Result<Framing, ReadError> {
+        let mut length = None;
+        let mut length_offset = 0;
+        let mut chunked = false;
+        let mut offset = 0;
+        for line in head.split(|&byte| byte == b'\n') {
+            let start = offset;
+            offset += line.len() + 1;
+            if start == 0 {
+                continue; // The request line.
+            }
+            let Some(colon) = line.iter().position(|&byte| byte == b':') else { continue };
+            let (name, value) = (&line[..colon], line[colon + 1..].trim_ascii());
+            if name.eq_ignore_ascii_case(b"Transfer-Encoding") {
+                for coding in value.split(|&byte| byte == b',').map(<[u8]>::trim_ascii) {
+                    if chunked || !coding.eq_ignore_ascii_case(b"chunked") {
+                        return Err(ParseError::UnsupportedTransferEncoding { offset: start }.into());
+                    }
+                    chunked = true;
+                }
+            }
+            if name.eq_ignore_ascii_case(b"Content-Length") {
+                let value = std::str::from_utf8(value).ok()
+                    .filter(|value| !value.is_empty() && value.bytes().all(|byte| byte.is_ascii_digit()))
+                    .and_then(|value| value.parse::<usize>().ok())
+                    .ok_or(ParseError::InvalidContentLength { offset: start })?;
+                if length.is_some_and(|length| length != value) {
+                    return Err(ParseError::InvalidContentLength { offset: start }.into());
+                }
+                length = Some(value);
+                length_offset = start;
+            }
+        }
+        match length {
+            Some(_) if chunked => Err(ParseError::InvalidContentLength { offset: length_offset }.into()),
+            Some(length) if length > self.max_body_size => Err(ReadError::PayloadTooLarge),
+            Some(length) => Ok(Framing::Length(length)),
+            None if chunked => Ok(Framing::Chunked),
+            None if self.require_length && has_body_method(head) => Err(ReadError::LengthRequired),
+            None => Ok(Framing::Length(0))
+        }
+    }
+}
+
+/// Whether the request line in `head` uses a method that normally sends a body.
+fn has_body_method(head: &[u8]) -> bool {
+    [&b"POST "[..], b"PUT ", b"PATCH "].iter().any(|method| head.starts_with(method))
+}
+
+/// Finds the end of the first `\r\n\r\n` in `buffer`.
+fn find_head_end(buffer: &[u8]) -> Option<usize> {
+    buffer
+        .windows(4)
+        .position(|w| w == b"\r\n\r\n")
+        .map(|position| position + 4)
+}
+
+////////////////////////////////////////////////////////////////////////////////////////////////////
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tracing_test::traced_test;
+
+    /// Hands out its input one byte per read, like a very slow client.
+    struct Drip<'a>(&'a [u8]);
+
+    impl<'a> Read for Drip<'a> {
+        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+            match self.0.split_first() {
+                Some((byte, rest)) if !buf.is_empty() => {
+                    buf[0] = *byte;
+                    self.0 = rest;
+                    Ok(1)
+                }
+                _ => Ok(0)
+            }
+        }
+    }
+
+    #[test]
+    #[traced_test]
+    fn read_request_works() {
+        let input =
+            b"GET /first HTTP/1.1\r\n\
+            Host: www.example.com\r\n\
+            \r\n\
+            POST /upload HTTP/1.1\r\n\
+            Content-Length: 11\r\n\
+            \r\n\
+            hello worldPUT /upload HTTP/1.1\r\n\
+            Transfer-Encoding: chunked\r\n\
+            \r\n\
+            5\r\nhello\r\n6\r\n world\r\n0\r\nExpires: never\r\n\r\n";
+        let mut reader = RequestReader::new(Drip(input), 1024);
+        assert_eq!(
+            reader.read_request().unwrap(),
+            RawRequest { head: b"GET /first HTTP/1.1\r\nHost: www.example.com\r\n\r\n", body: b"", trailers: b"" },
+            "The first request was not read correctly!"
+        );
+        assert_eq!(
+            reader.read_request().unwrap(),
+            RawRequest { head: b"POST /upload HTTP/1.1\r\nContent-Length: 11\r\n\r\n", body: b"hello world", trailers: b"" },
+            "The second request was not read correctly!"
+        );
+        assert_eq!(
+            reader.read_request().unwrap(),
+            RawRequest {
+                head: b"PUT /upload HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n",
+                body: b"hello world",
+                trailers: b"Expires: never\r\n\r\n"
+            },
+            "The third request was not read correctly!"
+        );
+        match reader.read_request() {
+            Err(ReadError::Io(e)) => assert_eq!(e.kind(), ErrorKind::UnexpectedEof),
+            other => panic!("Expected the end of the stream, got {:?}", other)
+        }
+
+        let long_line = format!("GET /{} HTTP/1.1\r\n\r\n", "a".repeat(64));
+        let mut reader = RequestReader::new(Drip(long_line.as_bytes()), 32);
+        assert!(matches!(reader.read_request(), Err(ReadError::Parse(ParseError::UriTooLong { offset: 32 }))));
+
+        let long_headers = format!("GET / HTTP/1.1\r\nCookie: {}\r\n\r\n", "a".repeat(64));
+        let mut reader = RequestReader::new(Drip(long_headers.as_bytes()), 32);
+        assert!(matches!(reader.read_request(), Err(ReadError::Parse(ParseError::HeadersTooLarge { offset: 32 }))));
+
+        let mut reader = RequestReader::new(Drip(input), 1024).max_body_size(10);
+        reader.read_request().unwrap();
+        assert!(matches!(reader.read_request(), Err(ReadError::PayloadTooLarge)));
+
+        let mut reader = RequestReader::new(Drip(b"POST / HTTP/1.1\r\n\r\n"), 1024).require_length(true);
+        assert!(matches!(reader.read_request(), Err(ReadError::LengthRequired)));
+
+        let mut reader = RequestReader::new(Drip(b"POST / HTTP/1.1\r\nContent-Length: -1\r\n\r\n"), 1024);
+        assert!(matches!(reader.read_request(), Err(ReadError::Parse(ParseError::InvalidContentLength { offset: 17 }))));
+
+        let mut reader = RequestReader::new(Drip(b"POST / HTTP/1.1\r\nTransfer-Encoding: gzip\r\n\r\n"), 1024);
+        assert!(matches!(
+            reader.read_request(),
+            Err(ReadError::Parse(ParseError::UnsupportedTransferEncoding { offset: 17 }))
+        ));
+    }
+}
@@ -1,5 +1,13 @@
+use std::borrow::Cow;
 use std::fmt::{Display, Formatter};
-use crate::http::{Version, Header};
+use std::io::Read;
+use crate::http::{Header, Method, Version};
+use crate::http::response::chunked::{ChunkedWriter, IterReader};
+
+pub use crate::http::response::builder::{InvalidHeader, ResponseBuilder};
+
+mod builder;
+pub mod chunked;
 
 #[repr(u16)]
 #[derive(Debug, Clone, Copy, Eq, PartialEq, Hash)]
@@ -481,7 +489,7 @@ impl StatusCode {
             StatusCode::MovedPermanently => "Moved Permanently",
             StatusCode::Found => "Found",
             StatusCode::SeeOther => "See Other",
-            StatusCode::NotModified => "Modified",
+            StatusCode::NotModified => "Not Modified",
             StatusCode::TemporaryRedirect => "Temporary Redirect",
             StatusCode::PermanentRedirect => "Permanent Redirect",
             StatusCode::BadRequest => "Bad Request",
@@ -534,40 +542,250 @@ impl From<StatusCode> for u16 {
     }
 }
 
-pub(crate) struct Response<'a> {
+/// The body of a response.
+pub enum Body<'a> {
+    /// A body known up front, sent with a `Content-Length`.
+    Full(Cow<'a, [u8]>),
+    /// A body produced while it is being sent, of unknown length.
+    ///
+    /// HTTP/1.1 responses send it with `Transfer-Encoding: chunked`, followed
+    /// by `trailers`. HTTP/1.0 clients cannot decode that, so their responses
+    /// send it as is and mark its end by closing the connection; the trailers
+    /// are dropped.
+    Stream {
+        source: Box<dyn Read + Send + 'a>,
+        trailers: Vec<Header<'a>>
+    }
+}
+
+impl<'a> Body<'a> {
+    /// A streamed body read from `source`.
+    pub fn stream<R: Read + Send + 'a>(source: R) -> Body<'a> {
+        Body::Stream { source: Box::new(source), trailers: vec![] }
+    }
+
+    /// A streamed body sent one item of `items` at a time.
+    pub fn chunks<I, T>(items: I) -> Body<'a>
+    where
+        I: IntoIterator<Item = T>,
+        I::IntoIter: Send + 'a,
+        T: AsRef<[u8]> + Send + 'a
+    {
+        Body::stream(IterReader::new(items))
+    }
+
+    /// Adds trailer fields to a streamed body. A full body has nowhere to put
+    /// them, so they are dropped.
+    pub fn with_trailers(mut self, fields: Vec<Header<'a>>) -> Body<'a> {
+        if let Body::Stream { trailers, .. } = &mut self {
+            *trailers = fields;
+        }
+        self
+    }
+}
+
+impl<'a> From<&'a str> for Body<'a> {
+    fn from(body: &'a str) -> Self {
+        Body::Full(Cow::Borrowed(body.as_bytes()))
+    }
+}
+
+impl<'a> From<String> for Body<'a> {
+    fn from(body: String) -> Self {
+        Body::Full(Cow::Owned(body.into_bytes()))
+    }
+}
+
+impl<'a> From<&'a [u8]> for Body<'a> {
+    fn from(body: &'a [u8]) -> Self {
+        Body::Full(Cow::Borrowed(body))
+    }
+}
+
+impl<'a> From<Vec<u8>> for Body<'a> {
+    fn from(body: Vec<u8>) -> Self {
+        Body::Full(Cow::Owned(body))
+    }
+}
+
+impl<'a> Display for Body<'a> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Body::Full(body) => write!(f, "{}", String::from_utf8_lossy(body)),
+            Body::Stream { .. } => write!(f, "<streamed>")
+        }
+    }
+}
+
+pub struct Response<'a> {
     pub version: Version<'a>,
     pub status_code: StatusCode,
     pub headers: Vec<Header<'a>>,
-    pub body: &'a str
+    pub body: Body<'a>
 }
 
 impl<'a> Response<'a> {
-    pub fn serialize<T: std::io::Write>(&self, writable: &mut T) -> std::io::Result<()> {
-        let mut headers = vec![];
-        for field in &self.headers {
-            let header = String::new() + field.name + ": " + field.value;
-            headers.push(header);
+    pub fn builder() -> ResponseBuilder<'a> {
+        ResponseBuilder::new()
+    }
+
+    /// A `200 OK` response with a plain text body.
+    pub fn text<B: Into<Body<'a>>>(body: B) -> Response<'a> {
+        Response::with_content_type("text/plain; charset=utf-8", body)
+    }
+
+    /// A `200 OK` response with an HTML body.
+    pub fn html<B: Into<Body<'a>>>(body: B) -> Response<'a> {
+        Response::with_content_type("text/html; charset=utf-8", body)
+    }
+
+    /// A `200 OK` response with a body that is already encoded as JSON.
+    pub fn json<B: Into<Body<'a>>>(body: B) -> Response<'a> {
+        Response::with_content_type("application/json", body)
+    }
+
+    /// A response sending the client to `location`, which is usually one of
+    /// the 3xx status codes.
+    pub fn redirect(status_code: StatusCode, location: &str) -> Result<Response<'a>, InvalidHeader> {
+        Response::builder()
+            .status(status_code)
+            .header("Location", location)
+            .body(Body::Full(Cow::Borrowed(b"")))
+    }
+
+    /// A response with `status_code` and nothing else.
+    pub fn empty(status_code: StatusCode) -> Response<'a> {
+        Response {
+            version: Version::HTTP1_1,
+            status_code,
+            headers: vec![],
+            body: Body::Full(Cow::Borrowed(b""))
+        }
+    }
+
+    /// The page sent when a request fails with `status_code`.
+    pub(crate) fn error(status_code: StatusCode) -> Response<'a> {
+        let mut response = Response::html(format!("<h2>{}</h2>", status_code.canonical_reason()));
+        response.status_code = status_code;
+        response
+    }
+
+    fn with_content_type<B: Into<Body<'a>>>(content_type: &'static str, body: B) -> Response<'a> {
+        Response {
+            version: Version::HTTP1_1,
+            status_code: StatusCode::Ok,
+            headers: vec![Header::new("Content-Type", content_type.as_bytes())],
+            body: body.into()
+        }
+    }
+
+    /// Writes the response to `writable`. A streamed body is read from its
+    /// source as it is written, so this can only be done once. The framing
+    /// headers, `Content-Length` and `Transfer-Encoding`, are worked out from
+    /// the body and replace any the response has. Responses whose status code
+    /// does not allow a body are sent without it, and without them.
+    ///
+    /// Fails with `InvalidInput` before writing anything if a header or
+    /// trailer would not make up a single, valid field line.
+    pub fn serialize<T: std::io::Write>(&mut self, writable: &mut T) -> std::io::Result<()> {
+        let chunked = self.serialize_head(writable)?;
+        if !self.has_body() {
+            return Ok(());
+        }
+        match &mut self.body {
+            Body::Full(body) => writable.write_all(body),
+            Body::Stream { source, trailers } if chunked => {
+                let mut chunks = ChunkedWriter::new(&mut *writable);
+                std::io::copy(source, &mut chunks)?;
+                chunks.finish(trailers)?;
+                Ok(())
+            }
+            Body::Stream { source, .. } => {
+                std::io::copy(source, writable)?;
+                Ok(())
+            }
+        }
+    }
+
+    /// Writes the response to a `method` request. The response to `HEAD`
+    /// is the head it would have with its body, framing headers included,
+    /// and nothing more.
+    pub(crate) fn serialize_for<T: std::io::Write>(&mut self, method: &Method, writable: &mut T) -> std::io::Result<()> {
+        match method {
+            Method::HEAD => self.serialize_head(writable).map(|_| ()),
+            _ => self.serialize(writable)
+        }
+    }
+
+    /// Writes the status line and headers, up to the empty line before the
+    /// body, returning whether a streamed body is to be sent chunked.
+    pub(crate) fn serialize_head<T: std::io::Write>(&self, writable: &mut T) -> std::io::Result<bool> {
+        let trailers = match &self.body {
+            Body::Stream { trailers, .. } => trailers.as_slice(),
+            Body::Full(_) => &[]
+        };
+        for field in self.headers.iter().chain(trailers) {
+            InvalidHeader::check(field.name(), field.value())
+                .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidInput, e))?;
         }
         write!(writable,
-            "\
-            {} {} {}\r\n\
-            {}\r\n\
-            \r\n\
-            {}",
+            "{} {} {}\r\n",
             self.version,
             u16::from(self.status_code),
-            self.status_code.canonical_reason(),
-            headers.join("\r\n"),
-            self.body
-        )
+            self.status_code.canonical_reason()
+        )?;
+        // The body is always framed as it really is, whatever the headers say.
+        let framing = |field: &Header| {
+            field.name.eq_ignore_ascii_case("Content-Length") || field.name.eq_ignore_ascii_case("Transfer-Encoding")
+        };
+        for field in self.headers.iter().filter(|field| !framing(field)) {
+            write_field(writable, field)?;
+        }
+        let framed = self.has_body();
+        let chunked = framed && !self.is_close_delimited();
+        match &self.body {
+            Body::Full(body) if framed => write!(writable, "Content-Length: {}\r\n", body.len())?,
+            Body::Full(_) => {}
+            Body::Stream { trailers, .. } if chunked => {
+                writable.write_all(b"Transfer-Encoding: chunked\r\n")?;
+                if !trailers.is_empty() {
+                    let names: Vec<&str> = trailers.iter().map(Header::name).collect();
+                    write!(writable, "Trailer: {}\r\n", names.join(", "))?;
+                }
+            }
+            Body::Stream { .. } => {}
+        }
+        writable.write_all(b"\r\n")?;
+        Ok(chunked)
+    }
+
+    /// Whether the end of the body can only be told by closing the
+    /// connection after it.
+    pub fn is_close_delimited(&self) -> bool {
+        matches!(self.body, Body::Stream { .. }) && self.version != Version::HTTP1_1 && self.has_body()
     }
+
+    /// Whether the status code allows a body, and therefore needs framing.
+    pub(crate) fn has_body(&self) -> bool {
+        !self.status_code.is_informational()
+            && self.status_code != StatusCode::NoContent
+            && self.status_code != StatusCode::NotModified
+    }
+}
+
+/// Writes `field` as a header line, keeping its value byte for byte.
+pub(crate) fn write_field<T: std::io::Write>(writable: &mut T, field: &Header) -> std::io::Result<()> {
+    writable.write_all(field.name.as_bytes())?;
+    writable.write_all(b": ")?;
+    writable.write_all(&field.value)?;
+    writable.write_all(b"\r\n")
 }
 
 impl<'a> Display for Response<'a> {
     fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
        let mut headers = vec![];
        for field in &self.headers {
-           let header = field.name.to_string() + ": " + field.value;
+           let header = field.to_string();
            headers.push(header);
        }
         write!(f,
@@ -603,6 +821,7 @@ impl<'a> Display for Response<'a> {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use tracing_test::traced_test;
 
     #[test]
     #[traced_test]
@@ -611,13 +830,166 @@ mod tests {
             version: Version::HTTP1_1,
             status_code: StatusCode::Ok,
             headers: vec![
-                Header {
-                    name: "Content-type",
-                    value: "text/html"
-                }
+                Header::new("Content-type", b"text/html")
             ],
-            body: "<h2>It works!</h2>"
+            body: Body::from("<h2>It works!</h2>")
         };
         println!("{}", response);
     }
+
+    #[test]
+    #[traced_test]
+    fn serialize_streams_bodies() {
+        let mut response = Response {
+            version: Version::HTTP1_1,
+            status_code: StatusCode::Ok,
+            headers: vec![],
+            body: Body::chunks(["hello", " world"])
+                .with_trailers(vec![Header::new("Expires", b"never")])
+        };
+        let mut output = vec![];
+        response.serialize(&mut output).unwrap();
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            "HTTP/1.1 200 OK\r\n\
+            Transfer-Encoding: chunked\r\n\
+            Trailer: Expires\r\n\
+            \r\n\
+            5\r\nhello\r\n6\r\n world\r\n0\r\nExpires: never\r\n\r\n",
+            "The chunked response was not serialized correctly!"
+        );
+
+        let mut response = Response {
+            version: Version::HTTP1_0,
+            status_code: StatusCode::Ok,
+            headers: vec![],
+            body: Body::stream("hello world".as_bytes())
+        };
+        assert!(response.is_close_delimited());
+        let mut output = vec![];
+        response.serialize(&mut output).unwrap();
+        assert_eq!(
+            String::from_utf8(output).unwrap(), "HTTP/1.0 200 OK\r\n\r\nhello world",
+            "The close-delimited response was not serialized correctly!"
+        );
+    }
+
+    #[test]
+    fn serialize_writes_owned_bodies() {
+        let mut response = Response {
+            version: Version::HTTP1_1,
+            status_code: StatusCode::Ok,
+            headers: vec![Header::new(String::from("X-Count"), 42.to_string().into_bytes())],
+            body: Body::from(format!("{} items", 42))
+        };
+        let mut output = vec![];
+        response.serialize(&mut output).unwrap();
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            "HTTP/1.1 200 OK\r\nX-Count: 42\r\nContent-Length: 8\r\n\r\n42 items",
+            "The owned response was not serialized correctly!"
+        );
+    }
+
+    #[test]
+    fn serialize_leaves_out_forbidden_bodies() {
+        for status_code in [StatusCode::Continue, StatusCode::NoContent, StatusCode::NotModified] {
+            let mut response = Response::text("hello");
+            response.status_code = status_code;
+            response.headers.push(Header::new("Content-Length", b"5".as_slice()));
+            let mut output = vec![];
+            response.serialize(&mut output).unwrap();
+            assert_eq!(
+                String::from_utf8(output).unwrap(),
+                format!("HTTP/1.1 {} {}\r\nContent-Type: text/plain; charset=utf-8\r\n\r\n",
+                    u16::from(status_code), status_code.canonical_reason()),
+                "A {} response was sent with a body!", u16::from(status_code)
+            );
+        }
+        let mut response = Response::text(Body::chunks(["hello"]));
+        response.status_code = StatusCode::NoContent;
+        let mut output = vec![];
+        response.serialize(&mut output).unwrap();
+        assert_eq!(String::from_utf8(output).unwrap(), "HTTP/1.1 204 No Content\r\nContent-Type: text/plain; charset=utf-8\r\n\r\n");
+    }
+
+    #[test]
+    fn serialize_frames_bodies_itself() {
+        let mut response = Response::text("hello");
+        response.headers.push(Header::new("Content-Length", b"500".as_slice()));
+        response.headers.push(Header::new("Transfer-Encoding", b"chunked".as_slice()));
+        let mut output = vec![];
+        response.serialize(&mut output).unwrap();
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            "HTTP/1.1 200 OK\r\nContent-Type: text/plain; charset=utf-8\r\nContent-Length: 5\r\n\r\nhello",
+            "The framing headers given were trusted!"
+        );
+        assert_eq!(StatusCode::NotModified.canonical_reason(), "Not Modified");
+    }
+
+    #[test]
+    fn builder_works() {
+        let mut response = Response::builder()
+            .status(StatusCode::Created)
+            .header("Location", "/users/42")
+            .header(String::from("X-Request-Id"), b"abc")
+            .body("created")
+            .unwrap();
+        let mut output = vec![];
+        response.serialize(&mut output).unwrap();
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            "HTTP/1.1 201 Created\r\n\
+            Location: /users/42\r\n\
+            X-Request-Id: abc\r\n\
+            Content-Length: 7\r\n\
+            \r\n\
+            created",
+            "The built response was not serialized correctly!"
+        );
+
+        let injected = Response::builder().header("X-Name", "a\r\nSet-Cookie: b=c").body("");
+        assert_eq!(injected.err(), Some(InvalidHeader::Value("X-Name".to_string())));
+        let injected = Response::builder().header("X-Name\r\nSet-Cookie", "b=c").body("");
+        assert_eq!(injected.err(), Some(InvalidHeader::Name("X-Name\r\nSet-Cookie".to_string())));
+        assert!(Response::redirect(StatusCode::Found, "/\nSet-Cookie: b=c").is_err());
+    }
+
+    #[test]
+    fn serialize_refuses_invalid_fields() {
+        let mut response = Response::text("hello");
+        response.headers.push(Header::new("X-Name", b"a\r\nSet-Cookie: b=c".as_slice()));
+        let mut output = vec![];
+        let error = response.serialize(&mut output).unwrap_err();
+        assert_eq!(error.kind(), std::io::ErrorKind::InvalidInput);
+        assert!(output.is_empty(), "A header with CRLF in it was partly written!");
+
+        let trailers = vec![Header::new("X-Checksum", b"a\r\n\r\nHTTP/1.1 200 OK".as_slice())];
+        let mut response = Response::text(Body::chunks(["hello"]).with_trailers(trailers));
+        let mut output = vec![];
+        let error = response.serialize(&mut output).unwrap_err();
+        assert_eq!(error.kind(), std::io::ErrorKind::InvalidInput);
+        assert!(output.is_empty(), "A trailer with CRLF in it was partly written!");
+    }
+
+    #[test]
+    fn constructors_work() {
+        let response = Response::json(r#"{"id":42}"#);
+        assert_eq!(response.status_code, StatusCode::Ok);
+        assert_eq!(response.headers, vec![Header::new("Content-Type", b"application/json")]);
+
+        let mut response = Response::redirect(StatusCode::SeeOther, "/login").unwrap();
+        let mut output = vec![];
+        response.serialize(&mut output).unwrap();
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            "HTTP/1.1 303 See Other\r\nLocation: /login\r\nContent-Length: 0\r\n\r\n"
+        );
+
+        let mut response = Response::empty(StatusCode::NoContent);
+        let mut output = vec![];
+        response.serialize(&mut output).unwrap();
+        assert_eq!(String::from_utf8(output).unwrap(), "HTTP/1.1 204 No Content\r\n\r\n");
+    }
 }
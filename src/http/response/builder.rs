@@ -0,0 +1,102 @@
+use std::borrow::Cow;
+use std::fmt::{Display, Formatter};
+use crate::http::{Version, Header};
+use crate::http::request::parser::{field_character, token_character};
+use crate::http::response::{Body, Response, StatusCode};
+
+/// Builds a `Response` one part at a time.
+///
+/// Header names and values are checked as they are added, so a value taken
+/// from user input cannot smuggle in extra header lines. The first invalid
+/// header is reported by `body`.
+pub struct ResponseBuilder<'a> {
+    version: Version<'a>,
+    status_code: StatusCode,
+    headers: Vec<Header<'a>>,
+    error: Option<InvalidHeader>
+}
+
+impl<'a> ResponseBuilder<'a> {
+    pub(crate) fn new() -> ResponseBuilder<'a> {
+        ResponseBuilder {
+            version: Version::HTTP1_1,
+            status_code: StatusCode::Ok,
+            headers: vec![],
+            error: None
+        }
+    }
+
+    pub fn version(mut self, version: Version<'a>) -> ResponseBuilder<'a> {
+        self.version = version;
+        self
+    }
+
+    pub fn status(mut self, status_code: StatusCode) -> ResponseBuilder<'a> {
+        self.status_code = status_code;
+        self
+    }
+
+    /// Adds a header. Several headers with the same name are all sent.
+    pub fn header<N, V>(mut self, name: N, value: V) -> ResponseBuilder<'a>
+    where
+        N: Into<Cow<'a, str>>,
+        V: AsRef<[u8]>
+    {
+        let name = name.into();
+        let value = value.as_ref();
+        if self.error.is_none() {
+            match InvalidHeader::check(&name, value) {
+                Ok(()) => self.headers.push(Header::new(name, value.to_vec())),
+                Err(error) => self.error = Some(error)
+            }
+        }
+        self
+    }
+
+    /// Finishes the response with `body`, or fails if any header was invalid.
+    pub fn body<B: Into<Body<'a>>>(self, body: B) -> Result<Response<'a>, InvalidHeader> {
+        match self.error {
+            Some(error) => Err(error),
+            None => Ok(Response {
+                version: self.version,
+                status_code: self.status_code,
+                headers: self.headers,
+                body: body.into()
+            })
+        }
+    }
+}
+
+/// A header that cannot be sent as given.
+#[derive(Clone, Debug, PartialEq)]
+pub enum InvalidHeader {
+    /// The name is empty or not a token.
+    Name(String),
+    /// The value of the named header contains a control character, such as
+    /// CR or LF.
+    Value(String)
+}
+
+impl InvalidHeader {
+    /// Checks that `name` and `value` make up a single header line.
+    pub(crate) fn check(name: &str, value: &[u8]) -> Result<(), InvalidHeader> {
+        if name.is_empty() || !name.bytes().all(token_character) {
+            Err(InvalidHeader::Name(name.to_string()))
+        } else if !value.iter().copied().all(field_character) {
+            Err(InvalidHeader::Value(name.to_string()))
+        } else {
+            Ok(())
+        }
+    }
+}
+
+impl Display for InvalidHeader {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            InvalidHeader::Name(name) => write!(f, "invalid header name {:?}", name),
+            InvalidHeader::Value(name) => write!(f, "invalid value for header {:?}", name)
+        }
+    }
+}
+
+impl std::error::Error for InvalidHeader {}
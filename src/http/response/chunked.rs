@@ -0,0 +1,109 @@
+use std::io::{Read, Write};
+use crate::http::Header;
+use crate::http::response::write_field;
+
+/// Writes everything it is given as `Transfer-Encoding: chunked` chunks.
+///
+/// Each call to `write` becomes one chunk, which is flushed straight away so
+/// the client sees the body as it is produced. The body is only complete once
+/// `finish` has written the last chunk and the trailers.
+pub struct ChunkedWriter<W: Write> {
+    inner: W
+}
+
+impl<W: Write> ChunkedWriter<W> {
+    pub fn new(inner: W) -> ChunkedWriter<W> {
+        ChunkedWriter { inner }
+    }
+
+    /// Ends the body with the last chunk, followed by `trailers`.
+    pub fn finish(mut self, trailers: &[Header]) -> std::io::Result<W> {
+        self.inner.write_all(b"0\r\n")?;
+        for field in trailers {
+            write_field(&mut self.inner, field)?;
+        }
+        self.inner.write_all(b"\r\n")?;
+        Ok(self.inner)
+    }
+}
+
+impl<W: Write> Write for ChunkedWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        // An empty chunk would end the body early.
+        if !buf.is_empty() {
+            write!(self.inner, "{:X}\r\n", buf.len())?;
+            self.inner.write_all(buf)?;
+            self.inner.write_all(b"\r\n")?;
+            self.inner.flush()?;
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// Adapts an iterator of byte strings into a `Read` source, handing out at
+/// most one item per read.
+pub struct IterReader<I: Iterator> {
+    items: I,
+    current: Option<I::Item>,
+    position: usize
+}
+
+impl<I> IterReader<I>
+where
+    I: Iterator,
+    I::Item: AsRef<[u8]>
+{
+    pub fn new<T: IntoIterator<IntoIter = I>>(items: T) -> IterReader<I> {
+        IterReader { items: items.into_iter(), current: None, position: 0 }
+    }
+}
+
+impl<I> Read for IterReader<I>
+where
+    I: Iterator,
+    I::Item: AsRef<[u8]>
+{
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        loop {
+            if let Some(current) = &self.current {
+                let remaining = &current.as_ref()[self.position..];
+                if !remaining.is_empty() {
+                    let size = remaining.len().min(buf.len());
+                    buf[..size].copy_from_slice(&remaining[..size]);
+                    self.position += size;
+                    return Ok(size);
+                }
+            }
+            match self.items.next() {
+                Some(item) => {
+                    self.current = Some(item);
+                    self.position = 0;
+                }
+                None => return Ok(0)
+            }
+        }
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////////////////////////////
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn chunked_writer_works() {
+        let mut writer = ChunkedWriter::new(vec![]);
+        std::io::copy(&mut IterReader::new(["hello", "", " world"]), &mut writer).unwrap();
+        let output = writer.finish(&[Header::new("Expires", b"never")]).unwrap();
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            "5\r\nhello\r\n6\r\n world\r\n0\r\nExpires: never\r\n\r\n",
+            "The chunked body was not written correctly!"
+        );
+    }
+}
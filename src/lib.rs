@@ -1,70 +1,590 @@
-use std::net::{SocketAddr, TcpListener};
-use std::str::FromStr;
+use std::io::{BufWriter, ErrorKind, Read, Write};
+use std::net::{Shutdown, TcpStream};
+#[cfg(unix)]
+use std::path::Path;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tracing::{debug, trace, warn};
+use crate::extract::Peer;
 use crate::http::{Header, Version};
 use crate::http::request::Request;
+use crate::http::request::parser::{parse_http_headers, ParseError};
+use crate::http::request::reader::{RawRequest, ReadError, RequestReader};
 use crate::http::response::{Response, StatusCode};
+use crate::listener::{Bound, Connection};
+use crate::pool::ThreadPool;
+use crate::shutdown::{Socket, Tracked};
 
+pub use crate::pool::Overflow;
+pub use crate::handler::{Handler, IntoResponse};
+pub use crate::listener::Listener;
+pub use crate::middleware::{Middleware, Next};
+pub use crate::proxy::ProxyProtocol;
+pub use crate::router::{Params, Router};
+pub use crate::shutdown::{ShutdownHandle, ShutdownSummary};
+#[cfg(feature = "tls")]
+pub use crate::tls::{Certificate, TlsConfig, TlsError};
+
+#[cfg(unix)]
+mod activation;
+#[cfg(feature = "async")]
+pub mod asynchronous;
+#[cfg(feature = "event-loop")]
+mod event_loop;
+pub mod extract;
+mod handler;
 pub mod http;
+mod listener;
+pub mod middleware;
+mod pool;
+mod proxy;
+mod router;
+mod shutdown;
+#[cfg(feature = "tls")]
+mod tls;
+#[cfg(unix)]
+mod unix;
+
+/// How long, and how much, a rejected connection is drained before it is
+/// closed. Rejections can happen on the accept loop, which a client sending
+/// slowly must not hold up.
+const DRAIN_TIME: Duration = Duration::from_millis(100);
+const DRAIN_SIZE: usize = 64 * 1024;
+
+pub struct Server {
+    listeners: Vec<Listener>,
+    workers: usize,
+    queue_depth: usize,
+    overflow: Overflow,
+    limits: Limits,
+    router: Arc<Router>,
+    shutdown: ShutdownHandle,
+    shutdown_timeout: Duration,
+}
 
-struct Server {
-    address: SocketAddr,
+/// Per-connection limits enforced by the workers.
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct Limits {
+    pub(crate) idle_timeout: Duration,
+    pub(crate) max_requests: usize,
+    pub(crate) max_head_size: usize,
+    pub(crate) max_body_size: usize,
+    pub(crate) require_length: bool,
+}
+
+impl Default for Limits {
+    fn default() -> Limits {
+        Limits {
+            idle_timeout: Duration::from_secs(5),
+            max_requests: 100,
+            max_head_size: 8192,
+            max_body_size: 1024 * 1024,
+            require_length: false,
+        }
+    }
 }
 
 impl Server {
     pub fn new(addr: &str) -> std::io::Result<Server> {
-        let address = SocketAddr::from_str(addr)
-            .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidInput, e.to_string()))?;
-        Ok(Server { address })
+        Ok(Server::from_listener(Listener::tcp(addr)?))
+    }
+
+    /// Creates a server that listens on a Unix domain socket at `path`, see
+    /// `Listener::unix`.
+    #[cfg(unix)]
+    pub fn unix<P: AsRef<Path>>(path: P) -> Server {
+        Server::from_listener(Listener::unix(path))
     }
 
-    pub fn serve(&self) -> std::io::Result<()> {
-        for result in TcpListener::bind(self.address)?.incoming() {
-            let mut stream = result?;
-            let mut buffer = [0u8; 4096];
-            let request = Server::deserialize(&mut stream, &mut buffer)?;
-            let response = Server::route(&request);
-            response.serialize(&mut stream)?;
+    /// Creates a server that listens on `listener`, such as one made from an
+    /// inherited socket with `Listener::from_tcp` or `Listener::activated`.
+    pub fn from_listener(listener: Listener) -> Server {
+        let workers = std::thread::available_parallelism().map_or(4, |n| n.get());
+        let limits = Limits::default();
+        Server {
+            listeners: vec![listener],
+            workers,
+            queue_depth: 64,
+            overflow: Overflow::default(),
+            limits,
+            router: Arc::new(Router::new()),
+            shutdown: ShutdownHandle::new(),
+            shutdown_timeout: Duration::from_secs(30),
         }
-        Ok(())
     }
 
-    fn deserialize<'a, T: std::io::Read>(stream: &mut T, mut buf: &'a mut [u8]) -> std::io::Result<Request<'a>> {
-        let size = stream.read(&mut buf)?;
-        println!("{}", std::str::from_utf8(&buf[0..size]).unwrap());
-        Request::try_from(
-            std::str::from_utf8(&buf[0..size])
-                .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e.to_string()))?
-        )
+    /// Listens on another address as well. All listeners share the workers,
+    /// limits and shutdown handle of the server.
+    pub fn listen(mut self, listener: Listener) -> Server {
+        self.listeners.push(listener);
+        self
+    }
+
+    /// Sets the router that answers requests. Without one, every request is
+    /// answered with `404 Not Found`.
+    pub fn router(mut self, router: Router) -> Server {
+        self.router = Arc::new(router);
+        self
+    }
+
+    /// Sets how many connections are handled concurrently.
+    ///
+    /// # Panics
+    ///
+    /// `serve` panics if this is zero.
+    pub fn workers(mut self, workers: usize) -> Server {
+        self.workers = workers;
+        self
+    }
+
+    /// Sets how many accepted connections may wait for a free worker.
+    pub fn queue_depth(mut self, queue_depth: usize) -> Server {
+        self.queue_depth = queue_depth;
+        self
+    }
+
+    /// Sets what happens to a connection accepted while the queue is full.
+    pub fn overflow(mut self, overflow: Overflow) -> Server {
+        self.overflow = overflow;
+        self
+    }
+
+    /// Sets how long a connection may sit idle waiting for its next request
+    /// before it is closed.
+    pub fn idle_timeout(mut self, idle_timeout: Duration) -> Server {
+        self.limits.idle_timeout = idle_timeout;
+        self
+    }
+
+    /// Sets how many requests are served over one connection before it is
+    /// closed. A value of one disables persistent connections.
+    pub fn max_requests(mut self, max_requests: usize) -> Server {
+        self.limits.max_requests = max_requests;
+        self
+    }
+
+    /// Sets how many bytes the request line and headers of a request may take
+    /// up together. Longer requests are answered with `414 URI Too Long` or
+    /// `431 Request Header Fields Too Large`.
+    pub fn max_head_size(mut self, max_head_size: usize) -> Server {
+        self.limits.max_head_size = max_head_size;
+        self
+    }
+
+    /// Sets how many bytes a request body may take up. Longer bodies are
+    /// answered with `413 Payload Too Large`.
+    pub fn max_body_size(mut self, max_body_size: usize) -> Server {
+        self.limits.max_body_size = max_body_size;
+        self
+    }
+
+    /// Sets whether `POST`, `PUT` and `PATCH` requests without a
+    /// `Content-Length` are answered with `411 Length Required`.
+    pub fn require_content_length(mut self, require_length: bool) -> Server {
+        self.limits.require_length = require_length;
+        self
+    }
+
+    /// Sets how long a shutdown waits for busy connections to finish before
+    /// closing them anyway.
+    pub fn shutdown_timeout(mut self, shutdown_timeout: Duration) -> Server {
+        self.shutdown_timeout = shutdown_timeout;
+        self
+    }
+
+    /// Serves HTTPS instead of plain HTTP on the address the server was
+    /// created with, see `Listener::tls`.
+    #[cfg(feature = "tls")]
+    pub fn tls(mut self, tls: TlsConfig) -> Server {
+        self.listeners[0].tls = Some(tls);
+        self
+    }
+
+    /// Reads PROXY protocol headers on the address the server was created
+    /// with, see `Listener::proxy_protocol`.
+    pub fn proxy_protocol(mut self, proxy: ProxyProtocol) -> Server {
+        self.listeners[0].proxy = Some(proxy);
+        self
+    }
+
+    /// Sets the permissions of the Unix domain socket the server was created
+    /// with, see `Listener::socket_mode`.
+    #[cfg(unix)]
+    pub fn socket_mode(mut self, mode: u32) -> Server {
+        self.listeners[0] = self.listeners[0].clone().socket_mode(mode);
+        self
+    }
+
+    /// A handle that stops this server, also from other threads.
+    pub fn shutdown_handle(&self) -> ShutdownHandle {
+        self.shutdown.clone()
+    }
+
+    /// Serves connections until the server is shut down through its
+    /// `ShutdownHandle`.
+    pub fn serve(&self) -> std::io::Result<ShutdownSummary> {
+        let mut bound = Vec::with_capacity(self.listeners.len());
+        for listener in &self.listeners {
+            match listener.bind() {
+                Ok(listener) => bound.push(listener),
+                Err(e) => {
+                    bound.into_iter().for_each(Bound::close);
+                    return Err(e);
+                }
+            }
+        }
+        self.serve_on(bound)
+    }
+
+    /// Serves the listeners in `bound`, which were bound for the server's
+    /// listeners in the same order.
+    fn serve_on(&self, bound: Vec<Bound>) -> std::io::Result<ShutdownSummary> {
+        for listener in &bound {
+            listener.watch(&self.shutdown)?;
+        }
+        let limits = self.limits;
+        let listeners = self.listeners.clone();
+        let router = Arc::clone(&self.router);
+        let shutdown = self.shutdown.clone();
+        let pool = ThreadPool::new(self.workers, self.queue_depth, move |(connection, index): (Connection, usize)| {
+            if shutdown.is_shutting_down() {
+                debug!("Closing a queued connection on shutdown");
+                return;
+            }
+            let listener = &listeners[index];
+            match connection {
+                Connection::Tcp(stream) => Server::serve_stream(stream, listener, limits, &router, &shutdown),
+                #[cfg(unix)]
+                Connection::Unix(stream) => Server::serve_stream(stream, listener, limits, &router, &shutdown),
+            }
+        });
+        let accepted = std::thread::scope(|scope| {
+            let pool = &pool;
+            let loops: Vec<_> = bound.iter()
+                .enumerate()
+                .map(|(index, listener)| scope.spawn(move || self.accept_loop(listener, index, pool)))
+                .collect();
+            loops.into_iter().try_for_each(|accept_loop| accept_loop.join().unwrap())
+        });
+        let summary = self.shutdown.drain(self.shutdown_timeout);
+        bound.into_iter().for_each(Bound::close);
+        // Handlers that ignore their aborted sockets must not hold up the
+        // caller past the timeout.
+        let deadline = Instant::now() + self.shutdown_timeout.saturating_sub(summary.elapsed);
+        let detached = pool.join_until(deadline);
+        if detached > 0 {
+            warn!("Left {} workers running past the shutdown timeout", detached);
+        }
+        accepted.map(|_| summary)
+    }
+
+    /// Hands the connections accepted on `listener` to the workers until
+    /// shutdown. Failing to accept because the listener is broken shuts the
+    /// whole server down; see `accept_backoff` for the failures that do not.
+    fn accept_loop(&self, listener: &Bound, index: usize, pool: &ThreadPool<(Connection, usize)>) -> std::io::Result<()> {
+        loop {
+            let result = listener.accept();
+            if self.shutdown.is_shutting_down() {
+                return Ok(());
+            }
+            let connection = match result {
+                Ok(connection) => connection,
+                Err(e) => {
+                    warn!("Failed to accept connection: {}", e);
+                    match Server::accept_backoff(&e) {
+                        Some(backoff) => {
+                            std::thread::sleep(backoff);
+                            continue;
+                        }
+                        None => {
+                            self.shutdown.shutdown();
+                            return Err(e);
+                        }
+                    }
+                }
+            };
+            let queued = match self.overflow {
+                Overflow::Reject => pool.try_execute((connection, index)),
+                Overflow::Block => pool.execute((connection, index)),
+            };
+            if let Err((connection, _)) = queued {
+                #[cfg(feature = "tls")]
+                if self.listeners[index].tls.is_some() {
+                    debug!("Closing connection: the queue is full");
+                    continue;
+                }
+                let router = self.listeners[index].router.as_ref().unwrap_or(&self.router);
+                match connection {
+                    Connection::Tcp(stream) => Server::reject(&stream, router, StatusCode::ServiceUnavailable),
+                    #[cfg(unix)]
+                    Connection::Unix(stream) => Server::reject(&stream, router, StatusCode::ServiceUnavailable),
+                }
+            }
+        }
+    }
+
+    /// Serves a connection accepted on `listener` on a worker.
+    fn serve_stream<S>(stream: S, listener: &Listener, limits: Limits, router: &Arc<Router>, shutdown: &ShutdownHandle)
+    where
+        S: Stream + Socket + Read + Write,
+        for<'s> &'s S: Read + Write,
+    {
+        let router = listener.router.as_ref().unwrap_or(router);
+        let tracked = shutdown.track(&stream);
+        if let Err(e) = stream.set_read_timeout(Some(limits.idle_timeout)) {
+            warn!("Failed to set idle timeout: {}", e);
+            return;
+        }
+        let peer = match Server::client(&stream, listener) {
+            Ok(peer) => peer,
+            Err(e) => {
+                warn!("Closing connection from {:?}: {}", stream.peer(), e);
+                return;
+            }
+        };
+        #[cfg(feature = "tls")]
+        if let Some(tls) = &listener.tls {
+            match tls.accept(stream) {
+                Ok(stream) => Server::accept::<tls::TlsStream<S>>(&stream, peer, tracked, limits, router),
+                Err(e) => warn!("Failed to start TLS session: {}", e),
+            }
+            return;
+        }
+        Server::accept(&stream, peer, tracked, limits, router)
+    }
+
+    /// The client at the other end of `stream`: its peer, or the client named
+    /// in the PROXY protocol header of a trusted proxy, which is read off the
+    /// stream.
+    fn client<S: Stream>(stream: &S, listener: &Listener) -> std::io::Result<Option<Peer>>
+    where
+        for<'s> &'s S: Read + Write,
+    {
+        let peer = stream.peer();
+        let (Some(proxy), Some(Peer::Tcp(address))) = (&listener.proxy, &peer) else {
+            return Ok(peer);
+        };
+        if !proxy.trusts(address.ip()) {
+            return Ok(peer);
+        }
+        match proxy::read_header(stream)? {
+            Some((client, destination)) => {
+                debug!("Connection from {} to {} through proxy {}", client, destination, address);
+                Ok(Some(Peer::Proxied { client, destination, proxy: *address }))
+            }
+            None => Ok(peer),
+        }
+    }
+
+    /// Handles a connection, or turns it away if it was accepted too late to
+    /// be tracked for shutdown.
+    fn accept<S: Stream>(stream: &S, peer: Option<Peer>, tracked: Option<Tracked>, limits: Limits, router: &Router)
+    where
+        for<'s> &'s S: Read + Write,
+    {
+        match tracked {
+            Some(tracked) => Server::handle(stream, peer, limits, router, &tracked),
+            None => Server::reject(stream, router, StatusCode::ServiceUnavailable),
+        }
     }
 
-    fn route<'a>(_request: &Request<'a>) -> Response<'a> {
-        Response {
-            version: Version::HTTP1_1,
-            status_code: StatusCode::Ok,
-            headers: vec![
-                Header {
-                    name: "Content-type",
-                    value: "text/html"
+    fn handle<S: Stream>(stream: &S, peer: Option<Peer>, limits: Limits, router: &Router, tracked: &Tracked)
+    where
+        for<'s> &'s S: Read + Write,
+    {
+        let mut reader = RequestReader::new(stream, limits.max_head_size)
+            .max_body_size(limits.max_body_size)
+            .require_length(limits.require_length);
+        let mut served = 0;
+        loop {
+            // A connection waiting for anything but its first request is idle,
+            // and is closed rather than waited for when shutting down.
+            if served > 0 && !tracked.idle() {
+                debug!("Closing idle connection for shutdown");
+                return;
+            }
+            let request = match Server::deserialize(&mut reader) {
+                Ok(request) => request,
+                Err(ReadError::Io(e)) if Server::is_idle(&e) => {
+                    debug!("Closing idle connection: {}", e);
+                    return;
+                }
+                Err(e) => {
+                    if let Some(status_code) = e.status_code() {
+                        Server::reject(stream, router, status_code);
+                    }
+                    warn!("Failed to handle connection: {}", e);
+                    return;
                 }
-            ],
-            body: "<h2>Hello, world!</h2>"
+            };
+            served += 1;
+            tracked.busy();
+            let mut response = Server::respond(router, &request, peer.clone());
+            let keep_open = served < limits.max_requests && !tracked.is_shutting_down();
+            let persistent = Server::connection(&request, &mut response, keep_open);
+            let mut writer = BufWriter::new(stream);
+            if let Err(e) = response.serialize_for(&request.method, &mut writer).and_then(|_| writer.flush()) {
+                warn!("Failed to handle connection: {}", e);
+                return;
+            }
+            if !persistent {
+                return;
+            }
+        }
+    }
+
+    /// Routes `request`, answering in the client's HTTP version.
+    pub(crate) fn respond(router: &Router, request: &Request<'_>, peer: Option<Peer>) -> Response<'static> {
+        let mut response = router.handle(request, peer);
+        if request.version == Version::HTTP1_0 {
+            response.version = Version::HTTP1_0;
+        }
+        response
+    }
+
+    /// Adds the `Connection` header to `response`, returning whether the
+    /// connection stays open after it. A connection that should close anyway
+    /// is not `keep_open`.
+    pub(crate) fn connection(request: &Request<'_>, response: &mut Response<'_>, keep_open: bool) -> bool {
+        let persistent = keep_open && request.keep_alive() && !response.is_close_delimited();
+        response.headers.push(Header::new(
+            "Connection",
+            if persistent { "keep-alive" } else { "close" }.as_bytes()
+        ));
+        persistent
+    }
+
+    /// Whether `error` means the client simply stopped sending requests.
+    pub(crate) fn is_idle(error: &std::io::Error) -> bool {
+        matches!(error.kind(), ErrorKind::UnexpectedEof | ErrorKind::WouldBlock | ErrorKind::TimedOut)
+    }
+
+    /// How long to wait before accepting again after failing with `error`, or
+    /// `None` if the listener itself is broken. A failure that only lost one
+    /// connection is retried at once. Running out of file descriptors or
+    /// memory is waited out, since connections closing frees them up again.
+    pub(crate) fn accept_backoff(error: &std::io::Error) -> Option<Duration> {
+        #[cfg(unix)]
+        if matches!(error.raw_os_error(), Some(libc::EMFILE | libc::ENFILE | libc::ENOBUFS | libc::ENOMEM)) {
+            return Some(Duration::from_millis(100));
+        }
+        match error.kind() {
+            ErrorKind::OutOfMemory => Some(Duration::from_millis(100)),
+            // Linux also reports errors of the network the connection came
+            // over, which are to be retried like this.
+            ErrorKind::ConnectionAborted
+            | ErrorKind::ConnectionReset
+            | ErrorKind::Interrupted
+            | ErrorKind::TimedOut
+            | ErrorKind::NetworkDown
+            | ErrorKind::NetworkUnreachable
+            | ErrorKind::HostUnreachable => Some(Duration::ZERO),
+            #[cfg(unix)]
+            _ if error.raw_os_error() == Some(libc::EPROTO) => Some(Duration::ZERO),
+            _ => None,
+        }
+    }
+
+    /// Answers with an error page for `status_code` and closes the connection.
+    fn reject<S: Stream>(mut stream: &S, router: &Router, status_code: StatusCode)
+    where
+        for<'s> &'s S: Read + Write,
+    {
+        let mut response = Server::rejection(router, status_code);
+        if let Err(e) = response.serialize(&mut stream).and_then(|_| stream.flush()) {
+            warn!("Failed to reject connection: {}", e);
+        }
+        // Drain whatever the client is still sending, so closing the socket
+        // does not reset the connection before the response is read.
+        stream.shutdown_write();
+        let deadline = Instant::now() + DRAIN_TIME;
+        let mut discard = [0u8; 1024];
+        let mut drained = 0;
+        while drained < DRAIN_SIZE {
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            if remaining.is_zero() || stream.set_read_timeout(Some(remaining)).is_err() {
+                break;
+            }
+            match stream.read(&mut discard) {
+                Ok(size) if size > 0 => drained += size,
+                _ => break,
+            }
         }
     }
+
+    /// The error page sent before closing a connection that failed with
+    /// `status_code`.
+    pub(crate) fn rejection(router: &Router, status_code: StatusCode) -> Response<'static> {
+        let mut response = router.error(status_code);
+        response.headers.push(Header::new("Connection", b"close"));
+        response
+    }
+
+    fn deserialize<T: std::io::Read>(reader: &mut RequestReader<T>) -> Result<Request<'_>, ReadError> {
+        Server::parse(reader.read_request()?)
+    }
+
+    /// Parses a request framed by a `RequestReader` or `RequestBuffer`.
+    pub(crate) fn parse(raw: RawRequest<'_>) -> Result<Request<'_>, ReadError> {
+        trace!("Received request\n{}", String::from_utf8_lossy(raw.head));
+        let mut request = Request::try_from(raw.head)?;
+        request.body = raw.body.into();
+        if !raw.trailers.is_empty() {
+            request.trailers = parse_http_headers(raw.trailers)
+                .map_err(|e| match e {
+                    nom::Err::Error(e) | nom::Err::Failure(e) => raw.trailers.len() - e.input.len(),
+                    nom::Err::Incomplete(_) => raw.trailers.len()
+                })
+                .map_err(|offset| ParseError::InvalidHeader { offset })?
+                .1;
+        }
+        Ok(request)
+    }
+}
+
+/// A connection the blocking server speaks HTTP over, read from and written
+/// to through shared references like a `TcpStream`.
+pub(crate) trait Stream
+where
+    for<'s> &'s Self: Read + Write,
+{
+    fn peer(&self) -> Option<Peer>;
+
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> std::io::Result<()>;
+
+    /// Tells the client nothing more is coming, while still reading from it.
+    fn shutdown_write(&self);
+}
+
+impl Stream for TcpStream {
+    fn peer(&self) -> Option<Peer> {
+        self.peer_addr().ok().map(Peer::Tcp)
+    }
+
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> std::io::Result<()> {
+        TcpStream::set_read_timeout(self, timeout)
+    }
+
+    fn shutdown_write(&self) {
+        let _ = self.shutdown(Shutdown::Write);
+    }
 }
 
 ////////////////////////////////////////////////////////////////////////////////////////////////////
 
 #[cfg(test)]
 mod tests {
-    use std::net::TcpStream;
-    use crate::{Request, Version, Header, deserialize, serve};
-    use crate::http::request::{Method, Endpoint};
+    use std::io::Read;
+    use std::net::{SocketAddr, TcpListener};
+    use std::thread;
+    use std::time::{Duration, Instant};
+    use super::*;
+    use crate::http::{Method, Endpoint, QueryString, RequestTarget};
+    use crate::http::response::Body;
 
     #[test]
-    fn deserialize_works<'a>() {
-        let mut stream: TcpStream;
-        //stream.write("")
-        let mut stream =
+    fn deserialize_works() {
+        let stream =
             "GET /index.html HTTP/1.1\r\n\
             Host: 127.0.0.1:8080\r\n\
             Connection: keep-alive\r\n\
@@ -75,48 +595,423 @@ mod tests {
             Accept-Language: da\r\n\
             \r\n\
             ";
-        let mut buf = [0u8; 4096];
-        assert_eq!(deserialize(&mut stream, &mut buf), Ok(
+        let mut reader = RequestReader::new(stream.as_bytes(), 4096);
+        assert_eq!(Server::deserialize(&mut reader).unwrap(),
             Request {
                 method: Method::GET,
-                endpoint: Endpoint {
-                    segments: vec!["index.html"],
-                    parameters: vec![],
+                target: RequestTarget::Origin(Endpoint {
+                    raw_path: "/index.html".into(),
+                    raw_query: None,
+                    segments: vec!["index.html".into()],
+                    query: QueryString::default(),
                     fragment: None
-                },
+                }),
                 version: Version::HTTP1_1,
                 headers: vec![
-                    Header {
-                        name: "Host",
-                        value: "127.0.0.1:8080"
-                    },
-                    Header {
-                        name: "Connection",
-                        value: "keep-alive"
-                    },
-                    Header {
-                        name: "User-Agent",
-                        value: "Mozilla/5.0 (Macintosh; Intel Mac OS X 10_15_7) AppleWebKit/537.36 (KHTML, like Gecko) Chrome/105.0.0.0 Safari/537.36"
-                    },
-                    Header {
-                        name: "Accept",
-                        value: "text/html,application/xhtml+xml,application/xml;q=0.9,image/avif,image/webp,image/apng,*/*;q=0.8"
-                    },
-                    Header {
-                        name: "Sec-GPC",
-                        value: "1"
-                    },
-                    Header {
-                        name: "Accept-Encoding",
-                        value: "gzip, deflate, br"
-                    },
-                    Header {
-                        name: "Accept-Language",
-                        value: "da"
-                    },
+                    Header::new("Host", b"127.0.0.1:8080"),
+                    Header::new("Connection", b"keep-alive"),
+                    Header::new("User-Agent", b"Mozilla/5.0 (Macintosh; Intel Mac OS X 10_15_7) AppleWebKit/537.36 (KHTML, like Gecko) Chrome/105.0.0.0 Safari/537.36"),
+                    Header::new("Accept", b"image/avif,image/webp,image/apng,image/svg+xml,image/*,*/*;q=0.8"),
+                    Header::new("Sec-GPC", b"1"),
+                    Header::new("Accept-Encoding", b"gzip, deflate, br"),
+                    Header::new("Accept-Language", b"da"),
                 ],
-                body: ""
+                body: b"".into(),
+                trailers: vec![]
+            }
+        )
+    }
+
+    #[test]
+    fn deserialize_decodes_chunked_bodies() {
+        let stream =
+            "POST /upload HTTP/1.1\r\n\
+            Transfer-Encoding: chunked\r\n\
+            \r\n\
+            5;name=value\r\n\
+            hello\r\n\
+            0\r\n\
+            Expires: never\r\n\
+            \r\n";
+        let mut reader = RequestReader::new(stream.as_bytes(), 4096);
+        let request = Server::deserialize(&mut reader).unwrap();
+        assert_eq!(&request.body[..], b"hello", "The body was not decoded correctly!");
+        assert_eq!(request.trailers, vec![Header::new("Expires", b"never")]);
+    }
+
+    fn server() -> Server {
+        let hello = || Response::html("<h2>Hello, world!</h2>");
+        Server::new("127.0.0.1:0").unwrap()
+            .router(Router::new().get("/*path", hello).post("/*path", hello))
+    }
+
+    fn spawn_server(server: Server) -> SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        thread::spawn(move || server.serve_on(vec![listener.into()]));
+        // Give the workers a moment to start waiting for connections.
+        thread::sleep(Duration::from_millis(100));
+        address
+    }
+
+    fn request(address: SocketAddr, request: &str) -> String {
+        let mut stream = TcpStream::connect(address).unwrap();
+        stream.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        stream.write_all(request.as_bytes()).unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        response
+    }
+
+    #[test]
+    fn stalled_client_does_not_block_others() {
+        let address = spawn_server(server().workers(2));
+        let _stalled = TcpStream::connect(address).unwrap();
+        let response = request(address, "GET /index.html HTTP/1.1\r\nConnection: close\r\n\r\n");
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "{}", response);
+    }
+
+    #[test]
+    fn overflow_answers_service_unavailable() {
+        let server = server().workers(1).queue_depth(0);
+        let address = spawn_server(server);
+        let _stalled = TcpStream::connect(address).unwrap();
+        thread::sleep(Duration::from_millis(100));
+        let response = request(address, "");
+        assert!(response.starts_with("HTTP/1.1 503 Service Unavailable\r\n"), "{}", response);
+    }
+
+    #[test]
+    fn trickling_rejected_clients_do_not_hold_up_accepting() {
+        let address = spawn_server(server().workers(1).queue_depth(0));
+        let _stalled = TcpStream::connect(address).unwrap();
+        thread::sleep(Duration::from_millis(100));
+        let mut trickling = TcpStream::connect(address).unwrap();
+        thread::spawn(move || {
+            for _ in 0..100 {
+                if trickling.write_all(b"x").is_err() {
+                    return;
+                }
+                thread::sleep(Duration::from_millis(20));
             }
-        ))
+        });
+        thread::sleep(Duration::from_millis(50));
+        let started = Instant::now();
+        let response = request(address, "");
+        assert!(response.starts_with("HTTP/1.1 503 Service Unavailable\r\n"), "{}", response);
+        assert!(started.elapsed() < Duration::from_secs(1), "The trickling client held up accepting!");
+    }
+
+    fn read_response(stream: &mut TcpStream) -> String {
+        let mut head = vec![];
+        let mut byte = [0u8; 1];
+        while !head.ends_with(b"\r\n\r\n") {
+            stream.read_exact(&mut byte).unwrap();
+            head.push(byte[0]);
+        }
+        let head = String::from_utf8(head).unwrap();
+        let length = head.lines()
+            .find_map(|line| line.strip_prefix("Content-Length: "))
+            .map_or(0, |length| length.parse().unwrap());
+        let mut body = vec![0u8; length];
+        stream.read_exact(&mut body).unwrap();
+        head + std::str::from_utf8(&body).unwrap()
+    }
+
+    fn assert_closed(stream: &mut TcpStream) {
+        assert_eq!(stream.read(&mut [0u8; 1]).unwrap(), 0, "The connection should have been closed!");
+    }
+
+    #[test]
+    fn keep_alive_serves_several_requests() {
+        let address = spawn_server(server());
+        let mut stream = TcpStream::connect(address).unwrap();
+        stream.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        for _ in 0..3 {
+            stream.write_all(b"GET / HTTP/1.1\r\nHost: localhost\r\n\r\n").unwrap();
+            let response = read_response(&mut stream);
+            assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "{}", response);
+            assert!(response.contains("Connection: keep-alive\r\n"), "{}", response);
+        }
+        stream.write_all(b"GET / HTTP/1.1\r\nConnection: close\r\n\r\n").unwrap();
+        assert!(read_response(&mut stream).contains("Connection: close\r\n"));
+        assert_closed(&mut stream);
+    }
+
+    #[test]
+    fn http1_0_closes_unless_asked_to_keep_alive() {
+        let address = spawn_server(server());
+        let mut stream = TcpStream::connect(address).unwrap();
+        stream.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        stream.write_all(b"GET / HTTP/1.0\r\nConnection: keep-alive\r\n\r\n").unwrap();
+        assert!(read_response(&mut stream).contains("Connection: keep-alive\r\n"));
+        stream.write_all(b"GET / HTTP/1.0\r\n\r\n").unwrap();
+        assert!(read_response(&mut stream).contains("Connection: close\r\n"));
+        assert_closed(&mut stream);
+    }
+
+    #[test]
+    fn keep_alive_honours_limits() {
+        let server = server()
+            .max_requests(2)
+            .idle_timeout(Duration::from_millis(200));
+        let address = spawn_server(server);
+        let mut stream = TcpStream::connect(address).unwrap();
+        stream.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        stream.write_all(b"GET / HTTP/1.1\r\n\r\n").unwrap();
+        assert!(read_response(&mut stream).contains("Connection: keep-alive\r\n"));
+        stream.write_all(b"GET / HTTP/1.1\r\n\r\n").unwrap();
+        assert!(read_response(&mut stream).contains("Connection: close\r\n"));
+        assert_closed(&mut stream);
+
+        let mut idle = TcpStream::connect(address).unwrap();
+        idle.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        assert_closed(&mut idle);
+    }
+
+    #[test]
+    fn oversized_heads_are_rejected() {
+        let address = spawn_server(server().max_head_size(64));
+        let response = request(address, &format!("GET /{} HTTP/1.1\r\n\r\n", "a".repeat(100)));
+        assert!(response.starts_with("HTTP/1.1 414 URI Too Long\r\n"), "{}", response);
+        let response = request(address, &format!("GET / HTTP/1.1\r\nCookie: {}\r\n\r\n", "a".repeat(100)));
+        assert!(response.starts_with("HTTP/1.1 431 Request Header Fields Too Large\r\n"), "{}", response);
+    }
+
+    #[test]
+    fn head_requests_get_the_head_of_the_get_response() {
+        let address = spawn_server(server());
+        let response = request(address, "HEAD / HTTP/1.1\r\n\r\nGET / HTTP/1.1\r\nConnection: close\r\n\r\n");
+        let (head, get) = response.split_once("\r\n\r\n").unwrap();
+        assert!(head.starts_with("HTTP/1.1 200 OK\r\n"), "{}", head);
+        assert!(head.ends_with("\r\nContent-Length: 22"), "The HEAD response lost its framing!");
+        assert!(get.starts_with("HTTP/1.1 200 OK\r\n"), "The HEAD response was sent with a body!");
+        assert!(get.ends_with("<h2>Hello, world!</h2>"), "{}", get);
+    }
+
+    #[test]
+    fn unknown_methods_are_not_implemented() {
+        let address = spawn_server(server());
+        let response = request(address, "M-SEARCH / HTTP/1.1\r\nConnection: close\r\n\r\n");
+        assert!(response.starts_with("HTTP/1.1 501 Not Implemented\r\n"), "{}", response);
+        let response = request(address, "GETX / HTTP/1.1\r\nConnection: close\r\n\r\n");
+        assert!(response.starts_with("HTTP/1.1 501 Not Implemented\r\n"), "{}", response);
+    }
+
+    #[test]
+    fn panicking_handlers_are_answered_with_500() {
+        let router = Router::new()
+            .get("/panic", || -> &'static str { panic!("handler failed") })
+            .get("/", || "still serving");
+        let address = spawn_server(server().router(router));
+        let response = request(address, "GET /panic HTTP/1.1\r\nConnection: close\r\n\r\n");
+        assert!(response.starts_with("HTTP/1.1 500 Internal Server Error\r\n"), "{}", response);
+        let response = request(address, "GET / HTTP/1.1\r\nConnection: close\r\n\r\n");
+        assert!(response.ends_with("still serving"), "The server stopped after a panic!");
+    }
+
+    #[test]
+    fn panics_outside_handlers_leave_the_workers_running() {
+        struct Broken;
+        impl Read for Broken {
+            fn read(&mut self, _: &mut [u8]) -> std::io::Result<usize> {
+                panic!("stream failed")
+            }
+        }
+        let router = Router::new()
+            .get("/broken", || Response::text(Body::stream(Broken)))
+            .get("/", || "still serving");
+        let address = spawn_server(server().workers(2).router(router));
+        for _ in 0..3 {
+            request(address, "GET /broken HTTP/1.1\r\nConnection: close\r\n\r\n");
+        }
+        let response = request(address, "GET / HTTP/1.1\r\nConnection: close\r\n\r\n");
+        assert!(response.ends_with("still serving"), "The workers died with the stream!");
+    }
+
+    #[test]
+    fn accept_failures_of_one_connection_are_retried() {
+        let error = |kind| std::io::Error::new(kind, "accept failed");
+        assert_eq!(Server::accept_backoff(&error(ErrorKind::ConnectionAborted)), Some(Duration::ZERO));
+        assert_eq!(Server::accept_backoff(&error(ErrorKind::InvalidInput)), None);
+        #[cfg(unix)]
+        assert!(
+            Server::accept_backoff(&std::io::Error::from_raw_os_error(libc::EMFILE)).is_some_and(|d| d > Duration::ZERO),
+            "Running out of file descriptors was not waited out!"
+        );
+    }
+
+    #[test]
+    fn malformed_requests_are_answered() {
+        let address = spawn_server(server());
+        let response = request(address, "GET /a%zz HTTP/1.1\r\n\r\n");
+        assert!(response.starts_with("HTTP/1.1 400 Bad Request\r\n"), "{}", response);
+        let response = request(address, "GET / HTTP/1.1\r\nNo colon\r\n\r\n");
+        assert!(response.starts_with("HTTP/1.1 400 Bad Request\r\n"), "{}", response);
+        let response = request(address, "GET / HTTP/2.0\r\n\r\n");
+        assert!(response.starts_with("HTTP/1.1 505 HTTP Version Not Supported\r\n"), "{}", response);
+        let response = request(address, "POST / HTTP/1.1\r\nTransfer-Encoding: gzip\r\n\r\n");
+        assert!(response.starts_with("HTTP/1.1 501 Not Implemented\r\n"), "{}", response);
+    }
+
+    #[test]
+    fn heads_split_across_segments_are_reassembled() {
+        let address = spawn_server(server());
+        let mut stream = TcpStream::connect(address).unwrap();
+        stream.set_nodelay(true).unwrap();
+        stream.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        for byte in b"GET / HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n" {
+            stream.write_all(&[*byte]).unwrap();
+            thread::sleep(Duration::from_millis(1));
+        }
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "{}", response);
+    }
+
+    #[test]
+    fn bodies_are_framed_by_content_length() {
+        let address = spawn_server(server().max_body_size(16));
+        let mut stream = TcpStream::connect(address).unwrap();
+        stream.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        stream.write_all(b"POST / HTTP/1.1\r\nContent-Length: 5\r\n\r\nhelloGET / HTTP/1.1\r\n\r\n").unwrap();
+        assert!(read_response(&mut stream).starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(read_response(&mut stream).starts_with("HTTP/1.1 200 OK\r\n"));
+        stream.write_all(b"POST / HTTP/1.1\r\nContent-Length: 17\r\n\r\n").unwrap();
+        let response = read_response(&mut stream);
+        assert!(response.starts_with("HTTP/1.1 413 Payload Too Large\r\n"), "{}", response);
+        assert_closed(&mut stream);
+    }
+
+    #[test]
+    fn chunked_bodies_are_decoded() {
+        let address = spawn_server(server());
+        let mut stream = TcpStream::connect(address).unwrap();
+        stream.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        stream.write_all(b"POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n5\r\nhello\r\n0\r\n\r\n").unwrap();
+        assert!(read_response(&mut stream).starts_with("HTTP/1.1 200 OK\r\n"));
+        stream.write_all(b"POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\nzz\r\n").unwrap();
+        let response = read_response(&mut stream);
+        assert!(response.starts_with("HTTP/1.1 400 Bad Request\r\n"), "{}", response);
+    }
+
+    #[test]
+    fn handlers_receive_extracted_arguments() {
+        use crate::extract::Path;
+        let router = Router::new().get("/users/:id", |Path(id): Path<u64>, peer: SocketAddr| {
+            format!("user {} for {}", id, peer.ip())
+        });
+        let address = spawn_server(Server::new("127.0.0.1:0").unwrap().router(router));
+        let response = request(address, "GET /users/42 HTTP/1.1\r\nConnection: close\r\n\r\n");
+        assert!(response.ends_with("\r\n\r\nuser 42 for 127.0.0.1"), "{}", response);
+        let response = request(address, "GET /users/ada HTTP/1.1\r\nConnection: close\r\n\r\n");
+        assert!(response.starts_with("HTTP/1.1 400 Bad Request\r\n"), "{}", response);
+        let response = request(address, "POST /users/42 HTTP/1.1\r\nConnection: close\r\n\r\n");
+        assert!(response.starts_with("HTTP/1.1 405 Method Not Allowed\r\n"), "{}", response);
+        assert!(response.contains("\r\nAllow: GET, HEAD\r\n"), "{}", response);
+    }
+
+    fn spawn_slow_server(server: Server, delay: Duration) -> (SocketAddr, ShutdownHandle, thread::JoinHandle<ShutdownSummary>) {
+        let router = Router::new()
+            .get("/", || "fast")
+            .get("/slow", move || {
+                thread::sleep(delay);
+                "slow"
+            });
+        let server = server.router(router);
+        let handle = server.shutdown_handle();
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let serving = thread::spawn(move || server.serve_on(vec![listener.into()]).unwrap());
+        thread::sleep(Duration::from_millis(100));
+        (address, handle, serving)
+    }
+
+    #[test]
+    fn shutdown_drains_busy_and_closes_idle_connections() {
+        let (address, handle, serving) = spawn_slow_server(
+            Server::new("127.0.0.1:0").unwrap().workers(4),
+            Duration::from_millis(500)
+        );
+        let mut idle = TcpStream::connect(address).unwrap();
+        idle.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        idle.write_all(b"GET / HTTP/1.1\r\n\r\n").unwrap();
+        assert!(read_response(&mut idle).ends_with("fast"));
+        let mut busy = TcpStream::connect(address).unwrap();
+        busy.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        busy.write_all(b"GET /slow HTTP/1.1\r\n\r\n").unwrap();
+        thread::sleep(Duration::from_millis(100));
+
+        handle.shutdown();
+        assert_closed(&mut idle);
+        let response = read_response(&mut busy);
+        assert!(response.contains("\r\nConnection: close\r\n"), "{}", response);
+        assert!(response.ends_with("slow"), "The busy request was not finished!");
+        assert_closed(&mut busy);
+
+        let summary = serving.join().unwrap();
+        assert_eq!((summary.closed_idle, summary.drained, summary.aborted), (1, 1, 0), "{:?}", summary);
+        assert!(TcpStream::connect(address).is_err(), "Still accepting connections after shutdown!");
+    }
+
+    #[test]
+    fn listeners_have_their_own_routers_and_share_shutdown() {
+        let admin = Router::new().get("/health", || "ok");
+        let server = server().listen(Listener::tcp("127.0.0.1:0").unwrap().router(admin));
+        let handle = server.shutdown_handle();
+        let (public, private) = (TcpListener::bind("127.0.0.1:0").unwrap(), TcpListener::bind("127.0.0.1:0").unwrap());
+        let addresses = (public.local_addr().unwrap(), private.local_addr().unwrap());
+        let serving = thread::spawn(move || server.serve_on(vec![public.into(), private.into()]).unwrap());
+
+        let response = request(addresses.0, "GET /health HTTP/1.1\r\nConnection: close\r\n\r\n");
+        assert!(response.ends_with("<h2>Hello, world!</h2>"), "{}", response);
+        let response = request(addresses.1, "GET /health HTTP/1.1\r\nConnection: close\r\n\r\n");
+        assert!(response.ends_with("ok"), "The listener did not use its own router!");
+        let response = request(addresses.1, "GET / HTTP/1.1\r\nConnection: close\r\n\r\n");
+        assert!(response.starts_with("HTTP/1.1 404 Not Found\r\n"), "{}", response);
+
+        handle.shutdown();
+        serving.join().unwrap();
+        assert!(TcpStream::connect(addresses.0).is_err(), "Still accepting connections after shutdown!");
+        assert!(TcpStream::connect(addresses.1).is_err(), "Still accepting connections after shutdown!");
+    }
+
+    #[test]
+    fn shutdown_before_serving_stops_at_once() {
+        let server = server();
+        let handle = server.shutdown_handle();
+        handle.shutdown();
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let (done, finished) = std::sync::mpsc::channel();
+        thread::spawn(move || done.send(server.serve_on(vec![listener.into()]).is_ok()));
+        assert_eq!(finished.recv_timeout(Duration::from_secs(5)), Ok(true), "The accept loop was never woken!");
+    }
+
+    #[test]
+    fn shutdown_aborts_connections_after_its_timeout() {
+        let (address, handle, serving) = spawn_slow_server(
+            Server::new("127.0.0.1:0").unwrap().shutdown_timeout(Duration::from_millis(200)),
+            Duration::from_secs(3)
+        );
+        let mut busy = TcpStream::connect(address).unwrap();
+        busy.write_all(b"GET /slow HTTP/1.1\r\n\r\n").unwrap();
+        thread::sleep(Duration::from_millis(100));
+        let started = Instant::now();
+        handle.shutdown();
+        let summary = serving.join().unwrap();
+        assert_eq!(summary.aborted, 1, "{:?}", summary);
+        assert!(summary.elapsed < Duration::from_secs(1), "Waited past the timeout!");
+        assert!(started.elapsed() < Duration::from_secs(1), "Serving outlived the timeout!");
+    }
+
+    #[test]
+    fn deserialize_accepts_binary_data() {
+        let mut stream = b"POST /upload HTTP/1.1\r\nX-Name: caf\xe9\r\nContent-Length: 4\r\n\r\n".to_vec();
+        stream.extend_from_slice(&[0x00, 0xff, 0xfe, 0x80]);
+        let mut reader = RequestReader::new(stream.as_slice(), 4096);
+        let request = Server::deserialize(&mut reader).unwrap();
+        assert_eq!(request.header("X-Name"), Some(&b"caf\xe9"[..]), "The obs-text header was not kept!");
+        assert_eq!(request.body[..], [0x00, 0xff, 0xfe, 0x80], "The binary body was not kept!");
+        assert!(request.body_str().is_err());
     }
 }
@@ -0,0 +1,248 @@
+//! The addresses a `Server` listens on, each with options of its own.
+
+use std::net::{SocketAddr, TcpListener, TcpStream};
+#[cfg(unix)]
+use std::os::unix::net::{UnixListener, UnixStream};
+#[cfg(unix)]
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::Arc;
+use socket2::{Domain, Protocol, Type};
+use tracing::warn;
+use crate::{ProxyProtocol, Router, ShutdownHandle};
+#[cfg(feature = "tls")]
+use crate::TlsConfig;
+
+/// An address a `Server` listens on, with the options for the connections
+/// accepted there.
+#[derive(Clone)]
+pub struct Listener {
+    pub(crate) address: Address,
+    /// The name the socket was passed under, for inherited sockets.
+    pub(crate) name: Option<String>,
+    /// The router for this listener, instead of the server's.
+    pub(crate) router: Option<Arc<Router>>,
+    /// Where PROXY protocol headers are expected from.
+    pub(crate) proxy: Option<ProxyProtocol>,
+    #[cfg(feature = "tls")]
+    pub(crate) tls: Option<TlsConfig>
+}
+
+/// Where a listener listens.
+#[derive(Clone, Debug)]
+pub(crate) enum Address {
+    Tcp(SocketAddr),
+    /// The path of a Unix domain socket, with the permissions to give it.
+    #[cfg(unix)]
+    Unix(PathBuf, Option<u32>),
+    /// A socket that is already listening, such as one inherited from the
+    /// process that started this one.
+    Listening(Arc<Bound>)
+}
+
+impl Listener {
+    /// Listens on a TCP address like `127.0.0.1:8080`.
+    ///
+    /// An IPv6 address only accepts IPv6 clients, so `0.0.0.0` and `[::]` can
+    /// be listened on together to take both on the same port.
+    pub fn tcp(addr: &str) -> std::io::Result<Listener> {
+        let address = SocketAddr::from_str(addr)
+            .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidInput, e.to_string()))?;
+        Ok(Listener::on(Address::Tcp(address)))
+    }
+
+    /// Listens on a Unix domain socket at `path`.
+    ///
+    /// A socket file left at `path` by a server that is no longer running is
+    /// replaced, and the file is removed again once the server has shut down.
+    /// Requests carry a `Peer::Unix` peer.
+    #[cfg(unix)]
+    pub fn unix<P: AsRef<Path>>(path: P) -> Listener {
+        Listener::on(Address::Unix(path.as_ref().to_path_buf(), None))
+    }
+
+    /// Serves a socket that is already listening, for example one handed
+    /// over by the previous instance of the server. The socket stays open
+    /// wherever else it is open.
+    pub fn from_tcp(listener: TcpListener) -> Listener {
+        Listener::on(Address::Listening(Arc::new(Bound::Tcp(listener))))
+    }
+
+    /// Serves a Unix domain socket that is already listening, like
+    /// `from_tcp`. Its file is left in place after shutdown.
+    #[cfg(unix)]
+    pub fn from_unix(listener: UnixListener) -> Listener {
+        Listener::on(Address::Listening(Arc::new(Bound::Unix(listener, None))))
+    }
+
+    pub(crate) fn on(address: Address) -> Listener {
+        Listener {
+            address,
+            name: None,
+            router: None,
+            proxy: None,
+            #[cfg(feature = "tls")]
+            tls: None
+        }
+    }
+
+    /// Sets the permissions of the Unix domain socket, like `0o660`, so only
+    /// some users can connect. Without this they follow the umask. TCP
+    /// listeners ignore this.
+    #[cfg(unix)]
+    pub fn socket_mode(mut self, mode: u32) -> Listener {
+        if let Address::Unix(_, socket_mode) = &mut self.address {
+            *socket_mode = Some(mode);
+        }
+        self
+    }
+
+    /// The name an inherited socket was passed under, see `activated`.
+    pub fn name(&self) -> Option<&str> {
+        self.name.as_deref()
+    }
+
+    /// Answers the requests on this listener with `router` instead of the
+    /// server's router, which listeners without one of their own share.
+    pub fn router(mut self, router: Router) -> Listener {
+        self.router = Some(Arc::new(router));
+        self
+    }
+
+    /// Expects connections from the sources `proxy` trusts to start with a
+    /// PROXY protocol header, version 1 or 2, naming the client they were
+    /// passed on for. Requests on them carry a `Peer::Proxied` peer, and
+    /// connections without a valid header are closed. Only TCP connections
+    /// can come from a trusted source.
+    pub fn proxy_protocol(mut self, proxy: ProxyProtocol) -> Listener {
+        self.proxy = Some(proxy);
+        self
+    }
+
+    /// Serves HTTPS instead of plain HTTP on this listener.
+    ///
+    /// Connections that arrive while the queue is full are closed without an
+    /// answer, since answering would mean a handshake on the accepting thread.
+    #[cfg(feature = "tls")]
+    pub fn tls(mut self, tls: TlsConfig) -> Listener {
+        self.tls = Some(tls);
+        self
+    }
+
+    pub(crate) fn bind(&self) -> std::io::Result<Bound> {
+        match &self.address {
+            Address::Tcp(address) => Ok(Bound::Tcp(bind_tcp(*address)?)),
+            #[cfg(unix)]
+            Address::Unix(path, mode) => Ok(Bound::Unix(crate::unix::bind(path, *mode)?, Some(path.clone()))),
+            Address::Listening(listener) => listener.try_clone()
+        }
+    }
+}
+
+fn bind_tcp(address: SocketAddr) -> std::io::Result<TcpListener> {
+    let socket = socket2::Socket::new(Domain::for_address(address), Type::STREAM, Some(Protocol::TCP))?;
+    if address.is_ipv6() {
+        socket.set_only_v6(true)?;
+    }
+    // Like the standard library, so a restarted server can bind again while
+    // connections of the old one linger.
+    #[cfg(unix)]
+    socket.set_reuse_address(true)?;
+    socket.bind(&address.into())?;
+    socket.listen(128)?;
+    Ok(socket.into())
+}
+
+/// A socket being listened on.
+#[derive(Debug)]
+pub(crate) enum Bound {
+    Tcp(TcpListener),
+    /// A Unix domain socket, with the path to remove once it is closed if it
+    /// was bound by the server.
+    #[cfg(unix)]
+    Unix(UnixListener, Option<PathBuf>)
+}
+
+impl From<TcpListener> for Bound {
+    fn from(listener: TcpListener) -> Bound {
+        Bound::Tcp(listener)
+    }
+}
+
+impl Bound {
+    pub(crate) fn accept(&self) -> std::io::Result<Connection> {
+        match self {
+            Bound::Tcp(listener) => Ok(Connection::Tcp(listener.accept()?.0)),
+            #[cfg(unix)]
+            Bound::Unix(listener, _) => Ok(Connection::Unix(listener.accept()?.0))
+        }
+    }
+
+    pub(crate) fn try_clone(&self) -> std::io::Result<Bound> {
+        match self {
+            Bound::Tcp(listener) => Ok(Bound::Tcp(listener.try_clone()?)),
+            #[cfg(unix)]
+            Bound::Unix(listener, _) => Ok(Bound::Unix(listener.try_clone()?, None))
+        }
+    }
+
+    /// Makes `shutdown` wake the accept loop of this listener.
+    pub(crate) fn watch(&self, shutdown: &ShutdownHandle) -> std::io::Result<()> {
+        match self {
+            Bound::Tcp(listener) => shutdown.watch_listener(listener.local_addr()?),
+            #[cfg(unix)]
+            Bound::Unix(listener, bound) => {
+                // A socket bound with a mode was moved to its path after
+                // binding, so its own address is out of date.
+                let address = listener.local_addr()?;
+                let path = bound.clone().or_else(|| address.as_pathname().map(Path::to_path_buf)).ok_or_else(|| {
+                    std::io::Error::new(std::io::ErrorKind::InvalidInput, format!("{:?} has no path", address))
+                })?;
+                shutdown.on_shutdown(move || {
+                    if let Err(e) = UnixStream::connect(&path) {
+                        warn!("Failed to wake listener on {}: {}", path.display(), e);
+                    }
+                });
+            }
+        }
+        Ok(())
+    }
+
+    /// Stops listening, removing the file of a Unix domain socket the server
+    /// bound itself.
+    pub(crate) fn close(self) {
+        #[cfg(unix)]
+        if let Bound::Unix(_, Some(path)) = self {
+            if let Err(e) = std::fs::remove_file(&path) {
+                warn!("Failed to remove socket {}: {}", path.display(), e);
+            }
+        }
+    }
+}
+
+/// A connection accepted from a `Bound`.
+pub(crate) enum Connection {
+    Tcp(TcpStream),
+    #[cfg(unix)]
+    Unix(UnixStream)
+}
+
+////////////////////////////////////////////////////////////////////////////////////////////////////
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ipv4_and_ipv6_listeners_share_a_port() {
+        if TcpListener::bind("[::1]:0").is_err() {
+            return; // No IPv6 here.
+        }
+        let Bound::Tcp(ipv4) = Listener::tcp("0.0.0.0:0").unwrap().bind().unwrap() else { unreachable!() };
+        let port = ipv4.local_addr().unwrap().port();
+        let ipv6 = Listener::tcp(&format!("[::]:{}", port)).unwrap().bind();
+        assert!(ipv6.is_ok(), "The IPv6 listener took over the IPv4 port!");
+        let taken = Listener::tcp(&format!("0.0.0.0:{}", port)).unwrap().bind();
+        assert!(taken.is_err(), "The port was bound twice!");
+    }
+}
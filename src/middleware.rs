@@ -0,0 +1,185 @@
+//! Behaviour shared by many routes, wrapped around their handlers.
+//!
+//! A `Middleware` is added to a `Router` with `Router::layer`, and wraps every
+//! route added to that router before it, including those of nested routers,
+//! as well as the router's answers to requests no route takes.
+//! Each middleware decides whether and how to call the rest of the stack
+//! through `Next`, so it can act before the handler, after it, or answer the
+//! request itself without calling the handler at all.
+
+use std::sync::Arc;
+use crate::extract::Context;
+use crate::http::request::Request;
+use crate::http::response::Response;
+
+pub(crate) type BoxedHandler = Box<dyn Fn(&Request<'_>, &Context) -> Response<'static> + Send + Sync>;
+
+/// Code that runs around a handler.
+pub trait Middleware: Send + Sync + 'static {
+    fn handle(&self, request: &Request<'_>, context: &Context, next: Next<'_>) -> Response<'static>;
+}
+
+/// The rest of the stack below a middleware, ending with the handler.
+pub struct Next<'a> {
+    handler: &'a (dyn Fn(&Request<'_>, &Context) -> Response<'static> + Send + Sync)
+}
+
+impl<'a> Next<'a> {
+    /// Passes `request` on and returns the response the rest of the stack
+    /// makes for it. The request does not have to be the one the middleware
+    /// was given.
+    pub fn run(self, request: &Request<'_>, context: &Context) -> Response<'static> {
+        (self.handler)(request, context)
+    }
+}
+
+/// Wraps `handler` in `middleware`.
+pub(crate) fn wrap(handler: BoxedHandler, middleware: Arc<dyn Middleware>) -> BoxedHandler {
+    Box::new(move |request: &Request<'_>, context: &Context| {
+        middleware.handle(request, context, Next { handler: &*handler })
+    })
+}
+
+/// A middleware that runs `f` in place of the stack below it, which `f` may
+/// call through `Next`.
+pub fn around<F>(f: F) -> Around<F>
+where
+    F: Fn(&Request<'_>, &Context, Next<'_>) -> Response<'static> + Send + Sync + 'static
+{
+    Around(f)
+}
+
+/// A middleware that runs `f` before the stack below it. If `f` returns a
+/// response, it is sent instead and the handler is not called.
+pub fn before<F>(f: F) -> Before<F>
+where
+    F: Fn(&Request<'_>, &Context) -> Option<Response<'static>> + Send + Sync + 'static
+{
+    Before(f)
+}
+
+/// A middleware that passes the response of the stack below it through `f`.
+pub fn after<F>(f: F) -> After<F>
+where
+    F: Fn(&Request<'_>, Response<'static>) -> Response<'static> + Send + Sync + 'static
+{
+    After(f)
+}
+
+pub struct Around<F>(F);
+
+impl<F> Middleware for Around<F>
+where
+    F: Fn(&Request<'_>, &Context, Next<'_>) -> Response<'static> + Send + Sync + 'static
+{
+    fn handle(&self, request: &Request<'_>, context: &Context, next: Next<'_>) -> Response<'static> {
+        (self.0)(request, context, next)
+    }
+}
+
+pub struct Before<F>(F);
+
+impl<F> Middleware for Before<F>
+where
+    F: Fn(&Request<'_>, &Context) -> Option<Response<'static>> + Send + Sync + 'static
+{
+    fn handle(&self, request: &Request<'_>, context: &Context, next: Next<'_>) -> Response<'static> {
+        match (self.0)(request, context) {
+            Some(response) => response,
+            None => next.run(request, context)
+        }
+    }
+}
+
+pub struct After<F>(F);
+
+impl<F> Middleware for After<F>
+where
+    F: Fn(&Request<'_>, Response<'static>) -> Response<'static> + Send + Sync + 'static
+{
+    fn handle(&self, request: &Request<'_>, context: &Context, next: Next<'_>) -> Response<'static> {
+        (self.0)(request, next.run(request, context))
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////////////////////////////
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Mutex;
+    use crate::Router;
+    use crate::http::{Header, Method};
+    use crate::http::response::StatusCode;
+
+    fn request(method: Method<'static>, target: &str, headers: &str) -> Request<'static> {
+        let head = format!("{} {} HTTP/1.1\r\n{}\r\n", method, target, headers);
+        Request::try_from(head.as_str()).unwrap().into_owned()
+    }
+
+    #[test]
+    fn layers_wrap_routes_in_order() {
+        let log = Arc::new(Mutex::new(vec![]));
+        let (inner, outer) = (Arc::clone(&log), Arc::clone(&log));
+        let router = Router::new()
+            .get("/", || "hello")
+            .layer(around(move |request, context, next| {
+                inner.lock().unwrap().push("inner");
+                next.run(request, context)
+            }))
+            .layer(around(move |request, context, next| {
+                outer.lock().unwrap().push("outer");
+                next.run(request, context)
+            }))
+            .layer(after(|_, mut response| {
+                response.headers.push(Header::new("X-Frame-Options", b"DENY"));
+                response
+            }));
+
+        let response = router.handle(&request(Method::GET, "/", ""), None);
+        assert_eq!(response.body.to_string(), "hello");
+        assert!(response.headers.contains(&Header::new("X-Frame-Options", b"DENY")));
+        assert_eq!(*log.lock().unwrap(), vec!["outer", "inner"], "The layers ran in the wrong order!");
+    }
+
+    #[test]
+    fn layers_apply_to_subtrees_and_can_short_circuit() {
+        let admin = Router::new()
+            .get("/stats", || "stats")
+            .layer(before(|request, _| match request.header("Authorization") {
+                Some(b"secret") => None,
+                _ => Some(Response::empty(StatusCode::Unauthorized))
+            }));
+        let router = Router::new()
+            .get("/", || "home")
+            .nest("/admin", admin);
+
+        let response = router.handle(&request(Method::GET, "/", ""), None);
+        assert_eq!(response.body.to_string(), "home", "The layer leaked out of its subtree!");
+        let response = router.handle(&request(Method::GET, "/admin/stats", ""), None);
+        assert_eq!(response.status_code, StatusCode::Unauthorized, "The layer did not short-circuit!");
+        let response = router.handle(&request(Method::GET, "/admin/stats", "Authorization: secret\r\n"), None);
+        assert_eq!(response.body.to_string(), "stats");
+    }
+
+    #[test]
+    fn layers_wrap_responses_to_unrouted_requests() {
+        let router = Router::new()
+            .get("/users", || "users")
+            .layer(after(|_, mut response| {
+                response.headers.push(Header::new("X-Frame-Options", b"DENY"));
+                response
+            }));
+
+        let framed = |method: Method<'static>, target: &str| {
+            let response = router.handle(&request(method, target, ""), None);
+            assert!(response.headers.contains(&Header::new("X-Frame-Options", b"DENY")), "The layer did not see the {:?}!", response.status_code);
+            response
+        };
+        assert_eq!(framed(Method::GET, "/missing").status_code, StatusCode::NotFound);
+        let response = framed(Method::POST, "/users");
+        assert_eq!(response.status_code, StatusCode::MethodNotAllowed);
+        assert!(response.headers.contains(&Header::new("Allow", b"GET, HEAD")));
+        assert_eq!(framed(Method::OTHER("M-SEARCH".into()), "/users").status_code, StatusCode::NotImplemented);
+    }
+}
@@ -0,0 +1,191 @@
+use std::panic::AssertUnwindSafe;
+use std::sync::mpsc::{self, Receiver, Sender, SyncSender, TrySendError};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::Instant;
+use tracing::{debug, error, trace};
+
+/// What the accept loop does with a new connection when every worker is busy
+/// and the queue is full.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub enum Overflow {
+    /// Answer with `503 Service Unavailable` and close the connection.
+    #[default]
+    Reject,
+    /// Stop accepting until a worker frees up a slot in the queue.
+    Block,
+}
+
+/// A fixed set of worker threads fed through a bounded queue.
+///
+/// Every job handed to the pool is passed to the same `handler`, which runs on
+/// whichever worker picks it up first. A job that panics does not take its
+/// worker down with it.
+pub struct ThreadPool<T: Send + 'static> {
+    workers: Vec<Worker>,
+    sender: Option<SyncSender<T>>,
+    exited: Mutex<Receiver<usize>>,
+}
+
+impl<T: Send + 'static> ThreadPool<T> {
+    /// Spawns `size` workers sharing a queue that holds at most `queue_depth`
+    /// pending jobs. A `queue_depth` of zero only hands a job over when a
+    /// worker is idle.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `size` is zero.
+    pub fn new<F>(size: usize, queue_depth: usize, handler: F) -> ThreadPool<T>
+    where
+        F: Fn(T) + Send + Sync + 'static,
+    {
+        assert!(size > 0, "a thread pool needs at least one worker");
+        let (sender, receiver) = mpsc::sync_channel(queue_depth);
+        let receiver = Arc::new(Mutex::new(receiver));
+        let handler = Arc::new(handler);
+        let (exit, exited) = mpsc::channel();
+        let workers = (0..size)
+            .map(|id| Worker::new(id, Arc::clone(&receiver), Arc::clone(&handler), exit.clone()))
+            .collect();
+        ThreadPool { workers, sender: Some(sender), exited: Mutex::new(exited) }
+    }
+
+    /// Queues `job`, waiting for room if the queue is full.
+    ///
+    /// The job is handed back if every worker has gone away.
+    pub fn execute(&self, job: T) -> Result<(), T> {
+        match &self.sender {
+            Some(sender) => sender.send(job).map_err(|e| e.0),
+            None => Err(job),
+        }
+    }
+
+    /// Queues `job` without waiting.
+    ///
+    /// The job is handed back if the queue is full or every worker has gone
+    /// away.
+    pub fn try_execute(&self, job: T) -> Result<(), T> {
+        match &self.sender {
+            Some(sender) => sender.try_send(job).map_err(|e| match e {
+                TrySendError::Full(job) | TrySendError::Disconnected(job) => job,
+            }),
+            None => Err(job),
+        }
+    }
+
+    /// Stops taking jobs and joins the workers that finish by `deadline`.
+    ///
+    /// Workers still busy at the deadline are detached and exit on their own
+    /// once their current job returns. Returns how many were left behind.
+    pub fn join_until(mut self, deadline: Instant) -> usize {
+        drop(self.sender.take());
+        let mut running = self.workers.len();
+        while running > 0 {
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            let Ok(id) = self.exited.get_mut().unwrap().recv_timeout(remaining) else { break };
+            if let Some(thread) = self.workers.iter_mut().find(|w| w.id == id).and_then(|w| w.thread.take()) {
+                let _ = thread.join();
+            }
+            running -= 1;
+        }
+        for worker in &mut self.workers {
+            if worker.thread.take().is_some() {
+                debug!("Detaching worker {} past the deadline", worker.id);
+            }
+        }
+        running
+    }
+}
+
+impl<T: Send + 'static> Drop for ThreadPool<T> {
+    fn drop(&mut self) {
+        drop(self.sender.take());
+        for worker in &mut self.workers {
+            debug!("Shutting down worker {}", worker.id);
+            if let Some(thread) = worker.thread.take() {
+                let _ = thread.join();
+            }
+        }
+    }
+}
+
+struct Worker {
+    id: usize,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl Worker {
+    fn new<T, F>(id: usize, receiver: Arc<Mutex<Receiver<T>>>, handler: Arc<F>, exit: Sender<usize>) -> Worker
+    where
+        T: Send + 'static,
+        F: Fn(T) + Send + Sync + 'static,
+    {
+        let thread = thread::spawn(move || {
+            let _exit = Exit(id, &exit);
+            loop {
+                let message = receiver.lock().unwrap().recv();
+                match message {
+                    Ok(job) => {
+                        trace!("Worker {} got a job", id);
+                        // Anything the job runs may panic, not only route
+                        // handlers; losing the worker would shrink the pool.
+                        if std::panic::catch_unwind(AssertUnwindSafe(|| handler(job))).is_err() {
+                            error!("Worker {} panicked running a job", id);
+                        }
+                    }
+                    Err(_) => {
+                        debug!("Worker {} disconnected", id);
+                        break;
+                    }
+                }
+            }
+        });
+        Worker { id, thread: Some(thread) }
+    }
+}
+
+/// Reports a worker's exit to its pool, however the thread ends.
+struct Exit<'a>(usize, &'a Sender<usize>);
+
+impl Drop for Exit<'_> {
+    fn drop(&mut self) {
+        let _ = self.1.send(self.0);
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////////////////////////////
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::mpsc::channel;
+    use std::time::Duration;
+
+    #[test]
+    fn try_execute_hands_back_jobs_when_full() {
+        let (started, wait_started) = channel();
+        let (release, wait_release) = channel::<()>();
+        let wait_release = Mutex::new(wait_release);
+        let pool = ThreadPool::new(1, 0, move |job: u32| {
+            started.send(job).unwrap();
+            let _ = wait_release.lock().unwrap().recv();
+        });
+        // Give the single worker a moment to start waiting for work.
+        thread::sleep(Duration::from_millis(50));
+        assert_eq!(pool.try_execute(1), Ok(()));
+        assert_eq!(wait_started.recv_timeout(Duration::from_secs(5)), Ok(1));
+        assert_eq!(pool.try_execute(2), Err(2));
+        drop(release);
+    }
+
+    #[test]
+    fn join_until_leaves_busy_workers_behind() {
+        let pool = ThreadPool::new(2, 0, |delay: Duration| thread::sleep(delay));
+        thread::sleep(Duration::from_millis(50));
+        pool.execute(Duration::from_secs(3)).unwrap();
+        let started = Instant::now();
+        let detached = pool.join_until(started + Duration::from_millis(100));
+        assert_eq!(detached, 1, "The busy worker was not left behind!");
+        assert!(started.elapsed() < Duration::from_secs(1), "Joined past the deadline!");
+    }
+}
@@ -0,0 +1,272 @@
+//! The PROXY protocol, with which a load balancer passing connections on
+//! tells the server where each one really came from.
+//!
+//! The load balancer sends a header before anything else on the connection,
+//! either as a line of text (version 1) or in binary (version 2). Only
+//! headers from trusted addresses are believed; anyone else could claim to be
+//! anyone.
+
+use std::io::{ErrorKind, Read};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::str::FromStr;
+
+/// The start of every version 2 header.
+const SIGNATURE: [u8; 12] = *b"\r\n\r\n\0\r\nQUIT\n";
+
+/// The longest version 1 header, including its line ending.
+const MAX_V1_LENGTH: usize = 107;
+
+/// Which peers a listener expects PROXY protocol headers from.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ProxyProtocol {
+    trusted: Vec<(IpAddr, u8)>
+}
+
+impl ProxyProtocol {
+    /// Expects headers from `sources`, given as addresses like `10.0.0.1` or
+    /// ranges like `10.0.0.0/8`. Connections from anywhere else are taken as
+    /// coming from their peer.
+    pub fn trusting<I, S>(sources: I) -> std::io::Result<ProxyProtocol>
+    where
+        I: IntoIterator<Item = S>,
+        S: AsRef<str>
+    {
+        let trusted = sources.into_iter()
+            .map(|source| parse_range(source.as_ref()))
+            .collect::<Result<_, _>>()
+            .map_err(|e| std::io::Error::new(ErrorKind::InvalidInput, e))?;
+        Ok(ProxyProtocol { trusted })
+    }
+
+    /// Whether headers from `address` are believed.
+    pub(crate) fn trusts(&self, address: IpAddr) -> bool {
+        let address = address.to_canonical();
+        self.trusted.iter().any(|(network, prefix)| match (network, address) {
+            (IpAddr::V4(network), IpAddr::V4(address)) => {
+                let mask = u32::MAX.checked_shl(32 - *prefix as u32).unwrap_or(0);
+                u32::from(*network) & mask == u32::from(address) & mask
+            }
+            (IpAddr::V6(network), IpAddr::V6(address)) => {
+                let mask = u128::MAX.checked_shl(128 - *prefix as u32).unwrap_or(0);
+                u128::from(*network) & mask == u128::from(address) & mask
+            }
+            _ => false
+        })
+    }
+}
+
+fn parse_range(range: &str) -> Result<(IpAddr, u8), String> {
+    let (address, prefix) = match range.split_once('/') {
+        Some((address, prefix)) => (address, Some(prefix)),
+        None => (range, None)
+    };
+    let address = IpAddr::from_str(address).map_err(|e| format!("Invalid address {}: {}", range, e))?;
+    let max = if address.is_ipv4() { 32 } else { 128 };
+    let prefix = match prefix {
+        Some(prefix) => prefix.parse::<u8>().map_err(|e| format!("Invalid prefix {}: {}", range, e))?,
+        None => max
+    };
+    if prefix > max {
+        return Err(format!("Invalid prefix {}: longer than the address", range));
+    }
+    Ok((address.to_canonical(), prefix))
+}
+
+/// Reads a PROXY protocol header, and nothing after it, returning the client
+/// and destination addresses it gives. A header that gives none, because the
+/// connection is the proxy's own or of an unsupported kind, yields `None`.
+pub(crate) fn read_header<R: Read>(mut reader: R) -> std::io::Result<Option<(SocketAddr, SocketAddr)>> {
+    // Both versions of the header are longer than this, so nothing after the
+    // header is read yet.
+    let mut header = vec![0u8; 8];
+    reader.read_exact(&mut header)?;
+    if header.starts_with(b"PROXY ") {
+        while !header.ends_with(b"\r\n") {
+            if header.len() == MAX_V1_LENGTH {
+                return Err(invalid("the header is too long"));
+            }
+            let mut byte = [0u8];
+            reader.read_exact(&mut byte)?;
+            header.push(byte[0]);
+        }
+        return parse_v1(&header[..header.len() - 2]);
+    }
+    if header[..] != SIGNATURE[..8] {
+        return Err(invalid("the connection does not start with a header"));
+    }
+    header.resize(16, 0);
+    reader.read_exact(&mut header[8..])?;
+    if header[..12] != SIGNATURE {
+        return Err(invalid("the signature is wrong"));
+    }
+    let mut addresses = vec![0u8; u16::from_be_bytes([header[14], header[15]]) as usize];
+    reader.read_exact(&mut addresses)?;
+    parse_v2(header[12], header[13], &addresses)
+}
+
+/// Parses a version 1 header without its line ending.
+fn parse_v1(line: &[u8]) -> std::io::Result<Option<(SocketAddr, SocketAddr)>> {
+    let line = std::str::from_utf8(line).map_err(|_| invalid("the header is not text"))?;
+    let fields: Vec<&str> = line.split(' ').collect();
+    match fields[..] {
+        ["PROXY", "UNKNOWN", ..] => Ok(None),
+        ["PROXY", family @ ("TCP4" | "TCP6"), source, destination, source_port, destination_port] => {
+            let address = |address: &str, port: &str| -> std::io::Result<SocketAddr> {
+                let address = IpAddr::from_str(address).map_err(|_| invalid("an address is invalid"))?;
+                if address.is_ipv4() != (family == "TCP4") {
+                    return Err(invalid("an address does not match the family"));
+                }
+                // Ports are plain decimal, without signs or leading zeros.
+                if port.starts_with(['0', '+']) && port != "0" {
+                    return Err(invalid("a port is invalid"));
+                }
+                Ok(SocketAddr::new(address, port.parse().map_err(|_| invalid("a port is invalid"))?))
+            };
+            Ok(Some((address(source, source_port)?, address(destination, destination_port)?)))
+        }
+        _ => Err(invalid("the header is malformed"))
+    }
+}
+
+/// Parses the rest of a version 2 header from its version and command byte,
+/// its family and protocol byte, and its addresses.
+fn parse_v2(version_command: u8, family: u8, addresses: &[u8]) -> std::io::Result<Option<(SocketAddr, SocketAddr)>> {
+    if version_command >> 4 != 2 {
+        return Err(invalid("the version is not 2"));
+    }
+    match version_command & 0x0f {
+        0 => return Ok(None),
+        1 => {}
+        _ => return Err(invalid("the command is unknown"))
+    }
+    let port = |bytes: &[u8]| u16::from_be_bytes([bytes[0], bytes[1]]);
+    match family {
+        // TCP over IPv4.
+        0x11 if addresses.len() >= 12 => {
+            let ip = |bytes: &[u8]| IpAddr::V4(Ipv4Addr::new(bytes[0], bytes[1], bytes[2], bytes[3]));
+            Ok(Some((
+                SocketAddr::new(ip(&addresses[0..4]), port(&addresses[8..10])),
+                SocketAddr::new(ip(&addresses[4..8]), port(&addresses[10..12]))
+            )))
+        }
+        // TCP over IPv6.
+        0x21 if addresses.len() >= 36 => {
+            let ip = |bytes: &[u8]| IpAddr::V6(Ipv6Addr::from(<[u8; 16]>::try_from(bytes).unwrap()));
+            Ok(Some((
+                SocketAddr::new(ip(&addresses[0..16]), port(&addresses[32..34])),
+                SocketAddr::new(ip(&addresses[16..32]), port(&addresses[34..36]))
+            )))
+        }
+        0x11 | 0x21 => Err(invalid("the addresses are cut short")),
+        // Anything else, such as UDP or Unix sockets, carries nothing a
+        // handler could use.
+        _ => Ok(None)
+    }
+}
+
+fn invalid(reason: &str) -> std::io::Error {
+    std::io::Error::new(ErrorKind::InvalidData, format!("Invalid PROXY protocol header: {}", reason))
+}
+
+////////////////////////////////////////////////////////////////////////////////////////////////////
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+    use std::net::{TcpListener, TcpStream};
+    use std::time::Duration;
+    use crate::extract::Peer;
+    use crate::{Router, Server};
+
+    fn header(bytes: &[u8]) -> std::io::Result<Option<(SocketAddr, SocketAddr)>> {
+        read_header(bytes)
+    }
+
+    fn addresses(source: &str, destination: &str) -> Option<(SocketAddr, SocketAddr)> {
+        Some((source.parse().unwrap(), destination.parse().unwrap()))
+    }
+
+    #[test]
+    fn version_1_headers_are_parsed() {
+        let mut bytes = &b"PROXY TCP4 192.0.2.1 198.51.100.2 56324 443\r\nGET / HTTP/1.1\r\n\r\n"[..];
+        assert_eq!(read_header(&mut bytes).unwrap(), addresses("192.0.2.1:56324", "198.51.100.2:443"));
+        assert_eq!(bytes, b"GET / HTTP/1.1\r\n\r\n", "Read past the header!");
+        assert_eq!(
+            header(b"PROXY TCP6 2001:db8::1 2001:db8::2 56324 443\r\n").unwrap(),
+            addresses("[2001:db8::1]:56324", "[2001:db8::2]:443")
+        );
+        assert_eq!(header(b"PROXY UNKNOWN\r\n").unwrap(), None);
+        assert!(header(b"PROXY TCP4 2001:db8::1 198.51.100.2 56324 443\r\n").is_err());
+        assert!(header(b"PROXY TCP4 192.0.2.1 198.51.100.2 056324 443\r\n").is_err());
+        assert!(header(b"PROXY TCP4 192.0.2.1 198.51.100.2 56324\r\n").is_err());
+        assert!(header(&[b"PROXY UNKNOWN ".as_slice(), &[b'x'; 100]].concat()).is_err(), "The header was too long!");
+        assert!(header(b"GET / HTTP/1.1\r\n\r\n").is_err());
+    }
+
+    #[test]
+    fn version_2_headers_are_parsed() {
+        let mut v4 = SIGNATURE.to_vec();
+        v4.extend_from_slice(&[0x21, 0x11, 0, 12, 192, 0, 2, 1, 198, 51, 100, 2, 0xdc, 0x04, 0x01, 0xbb]);
+        v4.extend_from_slice(b"GET /");
+        let mut bytes = &v4[..];
+        assert_eq!(read_header(&mut bytes).unwrap(), addresses("192.0.2.1:56324", "198.51.100.2:443"));
+        assert_eq!(bytes, b"GET /", "Read past the header!");
+
+        let mut v6 = SIGNATURE.to_vec();
+        v6.extend_from_slice(&[0x21, 0x21, 0, 36]);
+        v6.extend_from_slice(&"2001:db8::1".parse::<Ipv6Addr>().unwrap().octets());
+        v6.extend_from_slice(&"2001:db8::2".parse::<Ipv6Addr>().unwrap().octets());
+        v6.extend_from_slice(&[0xdc, 0x04, 0x01, 0xbb]);
+        assert_eq!(header(&v6).unwrap(), addresses("[2001:db8::1]:56324", "[2001:db8::2]:443"));
+
+        let mut local = SIGNATURE.to_vec();
+        local.extend_from_slice(&[0x20, 0x00, 0, 3, 1, 2, 3]);
+        assert_eq!(header(&local).unwrap(), None, "A LOCAL header gave addresses!");
+        let mut short = SIGNATURE.to_vec();
+        short.extend_from_slice(&[0x21, 0x11, 0, 4, 192, 0, 2, 1]);
+        assert!(header(&short).is_err());
+        let mut version = SIGNATURE.to_vec();
+        version.extend_from_slice(&[0x11, 0x11, 0, 0]);
+        assert!(header(&version).is_err());
+    }
+
+    #[test]
+    fn only_trusted_sources_are_believed() {
+        let proxy = ProxyProtocol::trusting(["10.0.0.0/8", "192.0.2.7", "2001:db8::/32"]).unwrap();
+        assert!(proxy.trusts("10.20.30.40".parse().unwrap()));
+        assert!(proxy.trusts("192.0.2.7".parse().unwrap()));
+        assert!(proxy.trusts("::ffff:10.0.0.1".parse().unwrap()), "A mapped IPv4 address was not trusted!");
+        assert!(proxy.trusts("2001:db8:1::1".parse().unwrap()));
+        assert!(!proxy.trusts("192.0.2.8".parse().unwrap()));
+        assert!(!proxy.trusts("11.0.0.1".parse().unwrap()));
+        assert!(ProxyProtocol::trusting(["10.0.0.0/33"]).is_err());
+        assert!(ProxyProtocol::trusting(["localhost"]).is_err());
+    }
+
+    #[test]
+    fn handlers_see_the_real_client() {
+        let router = Router::new().get("/", |peer: Peer, client: SocketAddr| format!("{:?} {}", peer, client));
+        let server = Server::new("127.0.0.1:0").unwrap()
+            .proxy_protocol(ProxyProtocol::trusting(["127.0.0.1"]).unwrap())
+            .router(router);
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        std::thread::spawn(move || server.serve_on(vec![listener.into()]));
+
+        let mut stream = TcpStream::connect(address).unwrap();
+        stream.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        stream.write_all(b"PROXY TCP4 192.0.2.1 198.51.100.2 56324 443\r\nGET / HTTP/1.1\r\nConnection: close\r\n\r\n").unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        assert!(response.contains("client: 192.0.2.1:56324, destination: 198.51.100.2:443"), "{}", response);
+        assert!(response.ends_with(" 192.0.2.1:56324"), "The client address was not the proxied one!");
+
+        let mut stream = TcpStream::connect(address).unwrap();
+        stream.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        stream.write_all(b"GET / HTTP/1.1\r\n\r\n").unwrap();
+        let mut response = String::new();
+        let _ = stream.read_to_string(&mut response);
+        assert!(response.is_empty(), "A trusted source was served without a header!");
+    }
+}
@@ -0,0 +1,461 @@
+use std::collections::HashMap;
+use std::fmt::{Debug, Formatter};
+use std::panic::AssertUnwindSafe;
+use std::sync::Arc;
+use tracing::{error, trace};
+use crate::extract::{Context, Peer};
+use crate::handler::Handler;
+use crate::http::{Header, Method};
+use crate::http::request::Request;
+use crate::http::response::{Response, StatusCode};
+use crate::middleware::{self, BoxedHandler, Middleware};
+
+/// Dispatches requests to handlers by method and path.
+///
+/// Paths are patterns of `/`-separated segments. A segment is matched
+/// literally, unless it is a named parameter such as `:id`, which matches any
+/// one segment, or a wildcard such as `*rest`, which may only come last and
+/// matches whatever is left of the path. Routes are tried in the order they
+/// were added, and the first one that matches handles the request.
+///
+/// Requests for a path no route matches are answered with `404 Not Found`,
+/// and requests for a path that is only routed for other methods with
+/// `405 Method Not Allowed` and an `Allow` header listing those methods.
+/// Requests with a method outside the standard ones that no route is for are
+/// answered with `501 Not Implemented`.
+/// A handler that panics is answered for with `500 Internal Server Error`.
+pub struct Router {
+    routes: Vec<Route>,
+    /// Answers the requests no route takes, wrapped in the layers like a
+    /// route would be.
+    unrouted: BoxedHandler,
+    error_pages: ErrorPages
+}
+
+/// Why no route took a request, for the router's `unrouted` handler.
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) enum Unrouted {
+    NotFound,
+    /// The path is routed for other methods, listed for the `Allow` header.
+    MethodNotAllowed(String),
+    NotImplemented
+}
+
+type ErrorPage = Arc<dyn Fn(StatusCode) -> Response<'static> + Send + Sync>;
+
+/// The custom error pages of a router, shared with the requests it handles so
+/// that rejected extractor arguments are answered with them too.
+#[derive(Clone, Default)]
+pub(crate) struct ErrorPages(Arc<HashMap<StatusCode, ErrorPage>>);
+
+impl ErrorPages {
+    /// The page sent when a request fails with `status_code`.
+    pub(crate) fn render(&self, status_code: StatusCode) -> Response<'static> {
+        match self.0.get(&status_code) {
+            Some(render) => {
+                let mut response = render(status_code);
+                response.status_code = status_code;
+                response
+            }
+            None => Response::error(status_code)
+        }
+    }
+}
+
+impl Debug for ErrorPages {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.debug_set().entries(self.0.keys()).finish()
+    }
+}
+
+/// Pages are closures, so only those of the same router are equal.
+impl PartialEq for ErrorPages {
+    fn eq(&self, other: &Self) -> bool {
+        Arc::ptr_eq(&self.0, &other.0)
+    }
+}
+
+struct Route {
+    method: Method<'static>,
+    pattern: Vec<Segment>,
+    handler: BoxedHandler
+}
+
+#[derive(Clone, Debug, PartialEq)]
+enum Segment {
+    Literal(String),
+    Parameter(String),
+    Wildcard(String)
+}
+
+impl Default for Router {
+    fn default() -> Router {
+        Router { routes: vec![], unrouted: Box::new(unrouted), error_pages: ErrorPages::default() }
+    }
+}
+
+impl Router {
+    pub fn new() -> Router {
+        Router::default()
+    }
+
+    /// Routes `method` requests for paths matching `path` to `handler`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `path` does not start with `/`, or has a wildcard anywhere
+    /// but in its last segment.
+    pub fn route<H, Args>(mut self, method: Method<'static>, path: &str, handler: H) -> Router
+    where
+        H: Handler<Args>
+    {
+        let pattern = parse_pattern(path);
+        let handler = Box::new(move |request: &Request<'_>, context: &Context| handler.call(request, context));
+        self.routes.push(Route { method, pattern, handler });
+        self
+    }
+
+    pub fn get<H, Args>(self, path: &str, handler: H) -> Router
+    where
+        H: Handler<Args>
+    {
+        self.route(Method::GET, path, handler)
+    }
+
+    pub fn post<H, Args>(self, path: &str, handler: H) -> Router
+    where
+        H: Handler<Args>
+    {
+        self.route(Method::POST, path, handler)
+    }
+
+    pub fn put<H, Args>(self, path: &str, handler: H) -> Router
+    where
+        H: Handler<Args>
+    {
+        self.route(Method::PUT, path, handler)
+    }
+
+    pub fn patch<H, Args>(self, path: &str, handler: H) -> Router
+    where
+        H: Handler<Args>
+    {
+        self.route(Method::PATCH, path, handler)
+    }
+
+    pub fn delete<H, Args>(self, path: &str, handler: H) -> Router
+    where
+        H: Handler<Args>
+    {
+        self.route(Method::DELETE, path, handler)
+    }
+
+    /// Wraps every route added so far, including nested ones, in
+    /// `middleware`. Routes added afterwards are not affected. The last layer
+    /// added is the outermost, and runs first. The responses to requests no
+    /// route takes, such as `404 Not Found`, go through every layer.
+    pub fn layer<M: Middleware>(mut self, middleware: M) -> Router {
+        let middleware: Arc<dyn Middleware> = Arc::new(middleware);
+        self.routes = self.routes.into_iter()
+            .map(|route| Route { handler: middleware::wrap(route.handler, Arc::clone(&middleware)), ..route })
+            .collect();
+        self.unrouted = middleware::wrap(self.unrouted, middleware);
+        self
+    }
+
+    /// Mounts the routes of `router` under `prefix`, keeping their layers.
+    /// Requests under `prefix` that none of them takes are answered by this
+    /// router, and go through its layers only.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `prefix` does not start with `/`, or has a wildcard.
+    pub fn nest(mut self, prefix: &str, router: Router) -> Router {
+        let prefix = parse_pattern(prefix);
+        assert!(
+            !prefix.iter().any(|segment| matches!(segment, Segment::Wildcard(_))),
+            "a nested router's prefix cannot have a wildcard"
+        );
+        for mut route in router.routes {
+            // The root of the nested router is the prefix itself.
+            if route.pattern == [Segment::Literal(String::new())] {
+                route.pattern.clear();
+            }
+            let mut pattern = prefix.clone();
+            pattern.append(&mut route.pattern);
+            self.routes.push(Route { pattern, ..route });
+        }
+        self
+    }
+
+    /// Renders the page sent for `status_code` with `render` instead of the
+    /// built-in one, keeping the status code. This covers the responses the
+    /// router and server make themselves: unrouted paths and methods,
+    /// panicking handlers, requests that cannot be read, and connections
+    /// turned away, as well as rejected extractor arguments. Error pages of a
+    /// nested router are not kept.
+    pub fn error_page<F>(mut self, status_code: StatusCode, render: F) -> Router
+    where
+        F: Fn(StatusCode) -> Response<'static> + Send + Sync + 'static
+    {
+        Arc::make_mut(&mut self.error_pages.0).insert(status_code, Arc::new(render));
+        self
+    }
+
+    /// Answers `request`, which came from `peer`, with the handler of the
+    /// first matching route. Requests without a path, for `CONNECT` or
+    /// `OPTIONS *`, match no route. A `HEAD` request is answered by the
+    /// `GET` route for its path unless a `HEAD` route matches.
+    pub fn handle(&self, request: &Request<'_>, peer: Option<Peer>) -> Response<'static> {
+        let segments = request.endpoint().map_or(&[][..], |endpoint| &endpoint.segments[..]);
+        let mut allowed: Vec<&Method<'static>> = vec![];
+        let mut fallback = None;
+        for route in &self.routes {
+            let Some(params) = matches(&route.pattern, segments) else {
+                continue;
+            };
+            if route.method == request.method {
+                return self.call(&route.handler, request, self.context(params, peer));
+            }
+            if request.method == Method::HEAD && route.method == Method::GET && fallback.is_none() {
+                fallback = Some((route, params));
+            }
+            if !allowed.contains(&&route.method) {
+                allowed.push(&route.method);
+            }
+        }
+        if let Some((route, params)) = fallback {
+            return self.call(&route.handler, request, self.context(params, peer));
+        }
+        // A method of its own that no route is for is not known here at all.
+        let unrouted = if matches!(request.method, Method::OTHER(_)) && !self.routes.iter().any(|route| route.method == request.method) {
+            Unrouted::NotImplemented
+        } else if allowed.is_empty() {
+            Unrouted::NotFound
+        } else {
+            let mut allow: Vec<String> = allowed.iter().map(|method| method.to_string()).collect();
+            if allowed.contains(&&Method::GET) && !allowed.contains(&&Method::HEAD) {
+                allow.push(Method::HEAD.to_string());
+            }
+            Unrouted::MethodNotAllowed(allow.join(", "))
+        };
+        let context = Context { unrouted: Some(unrouted), ..self.context(Params::default(), peer) };
+        self.call(&self.unrouted, request, context)
+    }
+
+    fn context(&self, params: Params, peer: Option<Peer>) -> Context {
+        Context { params, peer, error_pages: self.error_pages.clone(), unrouted: None }
+    }
+
+    /// Runs `handler`, answering with `500 Internal Server Error` if it
+    /// panics.
+    fn call(&self, handler: &BoxedHandler, request: &Request<'_>, context: Context) -> Response<'static> {
+        trace!("Routing {} request with {:?}", request.method, context.params);
+        // The request and context are only borrowed, so a panic leaves
+        // nothing behind half-changed.
+        match std::panic::catch_unwind(AssertUnwindSafe(|| handler(request, &context))) {
+            Ok(response) => response,
+            Err(panic) => {
+                let message = panic.downcast_ref::<&str>().copied()
+                    .or_else(|| panic.downcast_ref::<String>().map(String::as_str))
+                    .unwrap_or("unknown panic");
+                error!("Handler for {} {} panicked: {}", request.method, request.target, message);
+                self.error(StatusCode::InternalServerError)
+            }
+        }
+    }
+
+    /// The page sent when a request fails with `status_code`.
+    pub(crate) fn error(&self, status_code: StatusCode) -> Response<'static> {
+        self.error_pages.render(status_code)
+    }
+}
+
+/// Answers a request no route took with the error page for why. A context
+/// without a reason, which only a middleware can make up, gets `404 Not
+/// Found`.
+fn unrouted(_: &Request<'_>, context: &Context) -> Response<'static> {
+    match &context.unrouted {
+        Some(Unrouted::NotImplemented) => context.error_pages.render(StatusCode::NotImplemented),
+        Some(Unrouted::MethodNotAllowed(allow)) => {
+            let mut response = context.error_pages.render(StatusCode::MethodNotAllowed);
+            response.headers.push(Header::new("Allow", allow.clone().into_bytes()));
+            response
+        }
+        Some(Unrouted::NotFound) | None => context.error_pages.render(StatusCode::NotFound)
+    }
+}
+
+impl Debug for Router {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.debug_list()
+            .entries(self.routes.iter().map(|route| (&route.method, &route.pattern)))
+            .finish()
+    }
+}
+
+/// The path parameters a route captured, in the order they appear in its
+/// pattern. A wildcard captures the rest of the path, without its leading
+/// `/`.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Params {
+    params: Vec<(String, String)>
+}
+
+impl Params {
+    pub fn get(&self, name: &str) -> Option<&str> {
+        self.params.iter()
+            .find(|(param, _)| param == name)
+            .map(|(_, value)| value.as_str())
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.params.iter().map(|(name, value)| (name.as_str(), value.as_str()))
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.params.is_empty()
+    }
+
+    pub(crate) fn push(&mut self, name: &str, value: &str) {
+        self.params.push((name.to_string(), value.to_string()));
+    }
+}
+
+fn parse_pattern(path: &str) -> Vec<Segment> {
+    let Some(path) = path.strip_prefix('/') else {
+        panic!("route path {:?} does not start with '/'", path);
+    };
+    let segments: Vec<&str> = path.split('/').collect();
+    let last = segments.len() - 1;
+    segments.into_iter()
+        .enumerate()
+        .map(|(index, segment)| {
+            if let Some(name) = segment.strip_prefix(':') {
+                Segment::Parameter(name.to_string())
+            } else if let Some(name) = segment.strip_prefix('*') {
+                assert!(index == last, "route path {:?} has a wildcard before its end", path);
+                Segment::Wildcard(name.to_string())
+            } else {
+                Segment::Literal(segment.to_string())
+            }
+        })
+        .collect()
+}
+
+/// Matches `segments` against `pattern`, returning the captured parameters.
+fn matches<S: AsRef<str>>(pattern: &[Segment], segments: &[S]) -> Option<Params> {
+    let mut params = Params::default();
+    let mut segments = segments.iter().map(AsRef::as_ref);
+    for expected in pattern {
+        match expected {
+            Segment::Wildcard(name) => {
+                // Segments may be empty, so "/files/*rest" matches "/files"
+                // with an empty rest, and "/files/" too.
+                let rest: Vec<&str> = segments.by_ref().collect();
+                params.push(name, &rest.join("/"));
+                return Some(params);
+            }
+            Segment::Parameter(name) => params.push(name, segments.next()?),
+            Segment::Literal(literal) => {
+                if segments.next()? != literal {
+                    return None;
+                }
+            }
+        }
+    }
+    match segments.next() {
+        Some(_) => None,
+        None => Some(params)
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////////////////////////////
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::IntoResponse;
+    use crate::extract::Path;
+
+    fn request(method: Method<'static>, target: &str) -> Request<'static> {
+        let head = format!("{} {} HTTP/1.1\r\n\r\n", method, target);
+        Request::try_from(head.as_str()).unwrap().into_owned()
+    }
+
+    fn body(response: &Response) -> String {
+        response.body.to_string()
+    }
+
+    #[test]
+    fn router_works() {
+        let router = Router::new()
+            .get("/users", || "list")
+            .get("/users/:id", |Path(id): Path<u64>| format!("get {}", id))
+            .delete("/users/:id", |Path(id): Path<u64>| format!("delete {}", id))
+            .get("/files/*path", |Path(path): Path<String>| format!("file {}", path));
+
+        let response = router.handle(&request(Method::GET, "/users"), None);
+        assert_eq!(body(&response), "list");
+        let response = router.handle(&request(Method::GET, "/users/42"), None);
+        assert_eq!(body(&response), "get 42", "The path parameter was not captured!");
+        let response = router.handle(&request(Method::DELETE, "/users/42"), None);
+        assert_eq!(body(&response), "delete 42");
+        let response = router.handle(&request(Method::GET, "/files/css/site.css"), None);
+        assert_eq!(body(&response), "file css/site.css", "The wildcard did not capture the rest!");
+        let response = router.handle(&request(Method::GET, "/files"), None);
+        assert_eq!(body(&response), "file ");
+        let response = router.handle(&request(Method::GET, "http://example.com/users"), None);
+        assert_eq!(body(&response), "list", "The absolute form was not routed by its path!");
+
+        let response = router.handle(&request(Method::GET, "/users/42/posts"), None);
+        assert_eq!(response.status_code, StatusCode::NotFound);
+        let response = router.handle(&request(Method::GET, "/"), None);
+        assert_eq!(response.status_code, StatusCode::NotFound);
+        let response = router.handle(&request(Method::OPTIONS, "*"), None);
+        assert_eq!(response.status_code, StatusCode::NotFound);
+
+        let response = router.handle(&request(Method::PUT, "/users/42"), None);
+        assert_eq!(response.status_code, StatusCode::MethodNotAllowed);
+        assert!(
+            response.headers.contains(&Header::new("Allow", b"GET, DELETE, HEAD")),
+            "The Allow header did not list the routed methods!"
+        );
+        let response = router.handle(&request(Method::HEAD, "/users/42"), None);
+        assert_eq!(body(&response), "get 42", "HEAD was not answered like GET!");
+        let response = router.handle(&request(Method::OTHER("M-SEARCH".into()), "/users/42"), None);
+        assert_eq!(response.status_code, StatusCode::NotImplemented, "The unknown method was not turned away!");
+    }
+
+    #[test]
+    fn error_pages_can_be_replaced() {
+        let router = Router::new()
+            .get("/users", || "list")
+            .get("/panic", || -> &'static str { panic!("handler failed") })
+            .get("/users/:id", |Path(id): Path<u64>| format!("user {}", id))
+            .error_page(StatusCode::NotFound, |status_code| {
+                Response::text(format!("custom {}", u16::from(status_code)))
+            })
+            .error_page(StatusCode::InternalServerError, |_| (StatusCode::Ok, "oops").into_response())
+            .error_page(StatusCode::BadRequest, |_| Response::text("bad id"));
+
+        let response = router.handle(&request(Method::GET, "/missing"), None);
+        assert_eq!(response.status_code, StatusCode::NotFound);
+        assert_eq!(body(&response), "custom 404", "The custom error page was not used!");
+        let response = router.handle(&request(Method::GET, "/panic"), None);
+        assert_eq!(response.status_code, StatusCode::InternalServerError, "The error page changed the status code!");
+        assert_eq!(body(&response), "oops");
+        let response = router.handle(&request(Method::GET, "/users/ada"), None);
+        assert_eq!(response.status_code, StatusCode::BadRequest);
+        assert_eq!(body(&response), "bad id", "The rejection skipped the custom error page!");
+        let response = router.handle(&request(Method::POST, "/users"), None);
+        assert_eq!(response.status_code, StatusCode::MethodNotAllowed);
+        assert_eq!(body(&response), body(&Response::error(StatusCode::MethodNotAllowed)), "A built-in page was replaced!");
+    }
+
+    #[test]
+    #[should_panic]
+    fn wildcards_must_come_last() {
+        let _ = Router::new().get("/files/*path/edit", || StatusCode::Ok);
+    }
+}
@@ -0,0 +1,253 @@
+use std::collections::HashMap;
+use std::net::{Shutdown, SocketAddr, TcpStream};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Condvar, Mutex, MutexGuard};
+use std::time::{Duration, Instant};
+use tracing::{debug, info, warn};
+
+/// Stops a `Server` from another thread.
+///
+/// Once `shutdown` is called the server stops accepting connections, closes
+/// the ones waiting idle for their next request, and lets the others finish
+/// the request they are on. `serve` then returns a `ShutdownSummary` as soon
+/// as every connection is closed, or once the server's shutdown timeout has
+/// passed, closing whatever is left.
+#[derive(Clone, Default)]
+pub struct ShutdownHandle {
+    inner: Arc<Inner>
+}
+
+#[derive(Default)]
+struct Inner {
+    stopping: AtomicBool,
+    state: Mutex<State>,
+    closed: Condvar
+}
+
+#[derive(Default)]
+struct State {
+    next_id: u64,
+    connections: HashMap<u64, Connection>,
+    /// Addresses of the listeners, which are connected to so their blocking
+    /// accepts notice the shutdown.
+    listeners: Vec<SocketAddr>,
+    /// Called on shutdown, to stop servers that do not block on accept.
+    hooks: Vec<Box<dyn Fn() + Send>>,
+    started: Option<Instant>,
+    closed_idle: usize,
+    in_flight: usize
+}
+
+struct Connection {
+    socket: Box<dyn Socket>,
+    idle: bool
+}
+
+/// A connected socket that can be shut down from another thread.
+pub(crate) trait Socket: Send + 'static {
+    /// Another handle on the same socket.
+    fn duplicate(&self) -> std::io::Result<Box<dyn Socket>>;
+
+    fn shutdown(&self, how: Shutdown) -> std::io::Result<()>;
+}
+
+impl Socket for TcpStream {
+    fn duplicate(&self) -> std::io::Result<Box<dyn Socket>> {
+        Ok(Box::new(self.try_clone()?))
+    }
+
+    fn shutdown(&self, how: Shutdown) -> std::io::Result<()> {
+        TcpStream::shutdown(self, how)
+    }
+}
+
+/// How a shutdown went.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct ShutdownSummary {
+    /// Connections closed straight away because they were waiting idle for
+    /// their next request.
+    pub closed_idle: usize,
+    /// Connections that finished their request and closed in time.
+    pub drained: usize,
+    /// Connections still busy when the timeout ran out, which were closed
+    /// anyway.
+    pub aborted: usize,
+    /// How long it took from the call to `shutdown` until every connection
+    /// was closed.
+    pub elapsed: Duration
+}
+
+impl ShutdownHandle {
+    pub fn new() -> ShutdownHandle {
+        ShutdownHandle::default()
+    }
+
+    /// Starts shutting down. Calling this again has no effect.
+    pub fn shutdown(&self) {
+        let mut state = self.state();
+        if self.inner.stopping.swap(true, Ordering::SeqCst) {
+            return;
+        }
+        info!("Shutting down with {} open connections", state.connections.len());
+        state.started = Some(Instant::now());
+        let mut closed_idle = 0;
+        for connection in state.connections.values().filter(|connection| connection.idle) {
+            // Closing only the read side wakes the worker blocked on it, while
+            // a response it is about to write still gets through.
+            let _ = connection.socket.shutdown(Shutdown::Read);
+            closed_idle += 1;
+        }
+        state.closed_idle = closed_idle;
+        state.in_flight = state.connections.len() - closed_idle;
+        for address in &state.listeners {
+            wake(address);
+        }
+        for hook in &state.hooks {
+            hook();
+        }
+    }
+
+    pub fn is_shutting_down(&self) -> bool {
+        self.inner.stopping.load(Ordering::SeqCst)
+    }
+
+    /// Calls `shutdown` when the process receives `SIGTERM` or `SIGINT`.
+    #[cfg(unix)]
+    pub fn on_signals(&self) -> std::io::Result<()> {
+        use signal_hook::consts::{SIGINT, SIGTERM};
+        let mut signals = signal_hook::iterator::Signals::new([SIGTERM, SIGINT])?;
+        let handle = self.clone();
+        std::thread::spawn(move || {
+            if let Some(signal) = signals.forever().next() {
+                info!("Received signal {}", signal);
+                handle.shutdown();
+            }
+        });
+        Ok(())
+    }
+
+    /// Makes `shutdown` wake the accept loop of the listener on `address`,
+    /// or wakes it straight away if shutdown has already started.
+    pub(crate) fn watch_listener(&self, address: SocketAddr) {
+        let mut state = self.state();
+        if self.is_shutting_down() {
+            wake(&address);
+        } else {
+            state.listeners.push(address);
+        }
+    }
+
+    /// Calls `hook` on shutdown, or straight away if it has already started.
+    #[cfg_attr(not(any(unix, feature = "async", feature = "event-loop")), allow(dead_code))]
+    pub(crate) fn on_shutdown<F: Fn() + Send + 'static>(&self, hook: F) {
+        let mut state = self.state();
+        if self.is_shutting_down() {
+            hook();
+        } else {
+            state.hooks.push(Box::new(hook));
+        }
+    }
+
+    /// Tracks `socket` until the returned guard is dropped, or returns `None`
+    /// if the server is already shutting down.
+    pub(crate) fn track(&self, socket: &dyn Socket) -> Option<Tracked> {
+        let mut state = self.state();
+        if self.is_shutting_down() {
+            return None;
+        }
+        let socket = match socket.duplicate() {
+            Ok(socket) => socket,
+            Err(e) => {
+                warn!("Failed to track connection: {}", e);
+                return None;
+            }
+        };
+        let id = state.next_id;
+        state.next_id += 1;
+        state.connections.insert(id, Connection { socket, idle: false });
+        Some(Tracked { handle: self.clone(), id })
+    }
+
+    /// Waits for every tracked connection to close, closing the ones still
+    /// open after `timeout`.
+    pub(crate) fn drain(&self, timeout: Duration) -> ShutdownSummary {
+        let mut state = self.state();
+        let started = *state.started.get_or_insert_with(Instant::now);
+        let deadline = started + timeout;
+        while !state.connections.is_empty() {
+            let now = Instant::now();
+            if now >= deadline {
+                break;
+            }
+            state = self.inner.closed.wait_timeout(state, deadline - now).unwrap().0;
+        }
+        let aborted = state.connections.len();
+        for connection in state.connections.values() {
+            let _ = connection.socket.shutdown(Shutdown::Both);
+        }
+        if aborted > 0 {
+            warn!("Closed {} connections that were still busy", aborted);
+        }
+        let summary = ShutdownSummary {
+            closed_idle: state.closed_idle,
+            drained: state.in_flight.saturating_sub(aborted),
+            aborted,
+            elapsed: started.elapsed()
+        };
+        debug!("Shut down: {:?}", summary);
+        summary
+    }
+
+    fn state(&self) -> MutexGuard<'_, State> {
+        self.inner.state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+/// A connection the shutdown handle knows about, for as long as it is open.
+pub(crate) struct Tracked {
+    handle: ShutdownHandle,
+    id: u64
+}
+
+impl Tracked {
+    /// Marks the connection as waiting for its next request. Returns `false`
+    /// if it should be closed instead, because the server is shutting down.
+    pub(crate) fn idle(&self) -> bool {
+        self.set_idle(true)
+    }
+
+    /// Marks the connection as busy with a request.
+    pub(crate) fn busy(&self) {
+        self.set_idle(false);
+    }
+
+    pub(crate) fn is_shutting_down(&self) -> bool {
+        self.handle.is_shutting_down()
+    }
+
+    fn set_idle(&self, idle: bool) -> bool {
+        let mut state = self.handle.state();
+        if idle && self.handle.is_shutting_down() {
+            return false;
+        }
+        if let Some(connection) = state.connections.get_mut(&self.id) {
+            connection.idle = idle;
+        }
+        true
+    }
+}
+
+impl Drop for Tracked {
+    fn drop(&mut self) {
+        self.handle.state().connections.remove(&self.id);
+        self.handle.inner.closed.notify_all();
+    }
+}
+
+/// Connects to the listener on `address`, so that an accept loop blocked on it
+/// sees that it is shutting down.
+fn wake(address: &SocketAddr) {
+    if let Err(e) = TcpStream::connect_timeout(address, Duration::from_secs(1)) {
+        warn!("Failed to wake listener on {}: {}", address, e);
+    }
+}
@@ -0,0 +1,370 @@
+//! HTTPS for the blocking server, through rustls.
+//!
+//! A `TlsConfig` holds a default certificate and, optionally, certificates
+//! for particular server names, picked by the name the client asks for
+//! during the handshake (SNI). Certificates can be swapped while the server
+//! runs; connections already open keep the certificate they were made with.
+
+use std::collections::HashMap;
+use std::fmt::{Display, Formatter};
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, MutexGuard, RwLock};
+use std::time::Duration;
+use rustls::crypto::ring;
+use rustls::pki_types::pem::PemObject;
+use rustls::pki_types::{CertificateDer, PrivateKeyDer};
+use rustls::server::{ClientHello, ResolvesServerCert};
+use rustls::sign::CertifiedKey;
+use rustls::{ServerConfig, ServerConnection, StreamOwned};
+use tracing::debug;
+use crate::Stream;
+use crate::extract::Peer;
+
+/// A certificate chain with the private key of its first certificate.
+#[derive(Clone, Debug)]
+pub struct Certificate {
+    key: Arc<CertifiedKey>,
+    /// The files the certificate was loaded from, to load it again from.
+    files: Option<(PathBuf, PathBuf)>
+}
+
+impl Certificate {
+    /// Loads a certificate from PEM: `chain` holds the certificates, leaf
+    /// first, and `key` the private key, in PKCS #1, PKCS #8 or SEC1 form.
+    pub fn from_pem(chain: &[u8], key: &[u8]) -> Result<Certificate, TlsError> {
+        let chain = CertificateDer::pem_slice_iter(chain).collect::<Result<Vec<_>, _>>()?;
+        if chain.is_empty() {
+            return Err(TlsError::NoCertificates);
+        }
+        let key = match PrivateKeyDer::from_pem_slice(key) {
+            Ok(key) => key,
+            Err(rustls::pki_types::pem::Error::NoItemsFound) => return Err(TlsError::NoPrivateKey),
+            Err(e) => return Err(e.into())
+        };
+        let key = CertifiedKey::new(chain, ring::sign::any_supported_type(&key)?);
+        key.keys_match()?;
+        Ok(Certificate { key: Arc::new(key), files: None })
+    }
+
+    /// Loads a certificate from PEM files, like `from_pem`. The certificate
+    /// is loaded from them again by `TlsConfig::reload`.
+    pub fn from_pem_files(chain: impl AsRef<Path>, key: impl AsRef<Path>) -> Result<Certificate, TlsError> {
+        let (chain, key) = (chain.as_ref(), key.as_ref());
+        let mut certificate = Certificate::from_pem(&std::fs::read(chain)?, &std::fs::read(key)?)?;
+        certificate.files = Some((chain.to_path_buf(), key.to_path_buf()));
+        Ok(certificate)
+    }
+}
+
+/// How a `Server` speaks TLS.
+///
+/// Clones share their certificates, so a clone kept after handing the
+/// configuration to a server can replace them later.
+#[derive(Clone)]
+pub struct TlsConfig {
+    certificates: Arc<Certificates>,
+    config: Arc<ServerConfig>
+}
+
+#[derive(Debug)]
+struct Certificates {
+    default: RwLock<Certificate>,
+    names: RwLock<HashMap<String, Certificate>>
+}
+
+impl TlsConfig {
+    /// Creates a configuration that presents `certificate` to clients that
+    /// ask for no server name, or for one without a certificate of its own.
+    /// Only `http/1.1` is offered during protocol negotiation (ALPN).
+    pub fn new(certificate: Certificate) -> TlsConfig {
+        let certificates = Arc::new(Certificates {
+            default: RwLock::new(certificate),
+            names: RwLock::new(HashMap::new())
+        });
+        let mut config = ServerConfig::builder_with_provider(Arc::new(ring::default_provider()))
+            .with_safe_default_protocol_versions()
+            .expect("The default protocol versions are supported!")
+            .with_no_client_auth()
+            .with_cert_resolver(Arc::clone(&certificates) as Arc<dyn ResolvesServerCert>);
+        config.alpn_protocols = vec![b"http/1.1".to_vec()];
+        TlsConfig { certificates, config: Arc::new(config) }
+    }
+
+    /// Presents `certificate` to clients asking for `server_name`. A name
+    /// like `*.example.com` matches any single label in place of the `*`.
+    pub fn server_name(self, server_name: &str, certificate: Certificate) -> TlsConfig {
+        self.replace(Some(server_name), certificate);
+        self
+    }
+
+    /// Replaces the certificate for `server_name`, or the default one.
+    pub fn replace(&self, server_name: Option<&str>, certificate: Certificate) {
+        match server_name {
+            Some(name) => {
+                write(&self.certificates.names).insert(name.to_ascii_lowercase(), certificate);
+            }
+            None => *write(&self.certificates.default) = certificate
+        }
+    }
+
+    /// Loads every certificate that came from files again, for example after
+    /// they were renewed. If any of them fails to load, none are replaced.
+    pub fn reload(&self) -> Result<(), TlsError> {
+        let reload = |certificate: &Certificate| match &certificate.files {
+            Some((chain, key)) => Certificate::from_pem_files(chain, key),
+            None => Ok(certificate.clone())
+        };
+        let default = reload(&read(&self.certificates.default))?;
+        let names = read(&self.certificates.names).iter()
+            .map(|(name, certificate)| Ok((name.clone(), reload(certificate)?)))
+            .collect::<Result<HashMap<_, _>, TlsError>>()?;
+        *write(&self.certificates.default) = default;
+        *write(&self.certificates.names) = names;
+        debug!("Reloaded certificates");
+        Ok(())
+    }
+
+    /// Starts a TLS session over `stream`. The handshake happens on the first
+    /// read or write.
+    pub(crate) fn accept<S: Read + Write>(&self, stream: S) -> std::io::Result<TlsStream<S>> {
+        let connection = ServerConnection::new(Arc::clone(&self.config))
+            .map_err(std::io::Error::other)?;
+        Ok(TlsStream { session: Mutex::new(StreamOwned::new(connection, stream)) })
+    }
+}
+
+impl ResolvesServerCert for Certificates {
+    fn resolve(&self, client_hello: ClientHello<'_>) -> Option<Arc<CertifiedKey>> {
+        if let Some(name) = client_hello.server_name() {
+            let name = name.to_ascii_lowercase();
+            let names = read(&self.names);
+            let wildcard = name.split_once('.').map(|(_, parent)| format!("*.{}", parent));
+            let certificate = names.get(&name)
+                .or_else(|| wildcard.and_then(|wildcard| names.get(&wildcard)));
+            if let Some(certificate) = certificate {
+                return Some(Arc::clone(&certificate.key));
+            }
+        }
+        Some(Arc::clone(&read(&self.default).key))
+    }
+}
+
+fn read<T>(lock: &RwLock<T>) -> std::sync::RwLockReadGuard<'_, T> {
+    lock.read().unwrap_or_else(|e| e.into_inner())
+}
+
+fn write<T>(lock: &RwLock<T>) -> std::sync::RwLockWriteGuard<'_, T> {
+    lock.write().unwrap_or_else(|e| e.into_inner())
+}
+
+/// A TLS session over a connection, read from and written to through shared
+/// references like the connection itself.
+pub(crate) struct TlsStream<S: Read + Write> {
+    session: Mutex<StreamOwned<ServerConnection, S>>
+}
+
+impl<S: Read + Write> TlsStream<S> {
+    fn session(&self) -> MutexGuard<'_, StreamOwned<ServerConnection, S>> {
+        self.session.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Tells the client the session is over, without waiting for anything
+    /// the client still sends.
+    fn close_notify(&self) {
+        let mut session = self.session();
+        let StreamOwned { conn, sock } = &mut *session;
+        conn.send_close_notify();
+        while conn.wants_write() {
+            if let Err(e) = conn.write_tls(sock) {
+                debug!("Failed to close TLS session: {}", e);
+                return;
+            }
+        }
+    }
+}
+
+impl<S: Read + Write> Read for &TlsStream<S> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        self.session().read(buf)
+    }
+}
+
+impl<S: Read + Write> Write for &TlsStream<S> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.session().write(buf)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.session().flush()
+    }
+}
+
+impl<S: Stream + Read + Write> Stream for TlsStream<S>
+where
+    for<'s> &'s S: Read + Write,
+{
+    fn peer(&self) -> Option<Peer> {
+        self.session().sock.peer()
+    }
+
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> std::io::Result<()> {
+        self.session().sock.set_read_timeout(timeout)
+    }
+
+    fn shutdown_write(&self) {
+        self.close_notify();
+        self.session().sock.shutdown_write();
+    }
+}
+
+impl<S: Read + Write> Drop for TlsStream<S> {
+    fn drop(&mut self) {
+        if !self.session().conn.is_handshaking() {
+            self.close_notify();
+        }
+    }
+}
+
+/// Why a certificate could not be loaded.
+#[derive(Debug)]
+pub enum TlsError {
+    Io(std::io::Error),
+    /// The PEM could not be parsed.
+    Pem(rustls::pki_types::pem::Error),
+    NoCertificates,
+    NoPrivateKey,
+    /// The key is unsupported or does not belong to the certificate.
+    Rustls(rustls::Error)
+}
+
+impl Display for TlsError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            TlsError::Io(e) => write!(f, "Failed to read certificate: {}", e),
+            TlsError::Pem(e) => write!(f, "Invalid PEM: {}", e),
+            TlsError::NoCertificates => write!(f, "No certificates found"),
+            TlsError::NoPrivateKey => write!(f, "No private key found"),
+            TlsError::Rustls(e) => write!(f, "Invalid certificate: {}", e)
+        }
+    }
+}
+
+impl std::error::Error for TlsError {}
+
+impl From<std::io::Error> for TlsError {
+    fn from(e: std::io::Error) -> Self {
+        TlsError::Io(e)
+    }
+}
+
+impl From<rustls::pki_types::pem::Error> for TlsError {
+    fn from(e: rustls::pki_types::pem::Error) -> Self {
+        TlsError::Pem(e)
+    }
+}
+
+impl From<rustls::Error> for TlsError {
+    fn from(e: rustls::Error) -> Self {
+        TlsError::Rustls(e)
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////////////////////////////
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::{SocketAddr, TcpListener, TcpStream};
+    use std::thread;
+    use rustls::pki_types::ServerName;
+    use rustls::{ClientConfig, ClientConnection, RootCertStore};
+    use crate::{Router, Server};
+
+    struct SelfSigned {
+        chain: String,
+        key: String,
+        der: CertificateDer<'static>
+    }
+
+    fn self_signed(name: &str) -> SelfSigned {
+        let rcgen::CertifiedKey { cert, key_pair } = rcgen::generate_simple_self_signed(vec![name.to_string()]).unwrap();
+        SelfSigned { chain: cert.pem(), key: key_pair.serialize_pem(), der: cert.der().clone() }
+    }
+
+    fn certificate(signed: &SelfSigned) -> Certificate {
+        Certificate::from_pem(signed.chain.as_bytes(), signed.key.as_bytes()).unwrap()
+    }
+
+    fn spawn_server(tls: TlsConfig) -> SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let server = Server::new("127.0.0.1:0").unwrap()
+            .router(Router::new().get("/", || "secret"))
+            .tls(tls);
+        thread::spawn(move || server.serve_on(vec![listener.into()]));
+        address
+    }
+
+    /// Requests `/` from `server_name`, trusting only `trusted`, returning the
+    /// negotiated protocol and the response.
+    fn get(address: SocketAddr, server_name: &str, trusted: &SelfSigned) -> std::io::Result<(Option<Vec<u8>>, String)> {
+        let mut roots = RootCertStore::empty();
+        roots.add(trusted.der.clone()).unwrap();
+        let mut config = ClientConfig::builder_with_provider(Arc::new(ring::default_provider()))
+            .with_safe_default_protocol_versions()
+            .unwrap()
+            .with_root_certificates(roots)
+            .with_no_client_auth();
+        config.alpn_protocols = vec![b"h2".to_vec(), b"http/1.1".to_vec()];
+        let name = ServerName::try_from(server_name.to_string()).unwrap();
+        let connection = ClientConnection::new(Arc::new(config), name).unwrap();
+        let socket = TcpStream::connect(address)?;
+        socket.set_read_timeout(Some(Duration::from_secs(5)))?;
+        let mut stream = StreamOwned::new(connection, socket);
+        stream.write_all(b"GET / HTTP/1.1\r\nConnection: close\r\n\r\n")?;
+        let mut response = String::new();
+        stream.read_to_string(&mut response)?;
+        Ok((stream.conn.alpn_protocol().map(<[u8]>::to_vec), response))
+    }
+
+    #[test]
+    fn certificates_are_picked_by_server_name() {
+        let (localhost, example, wildcard) = (self_signed("localhost"), self_signed("example.test"), self_signed("*.example.test"));
+        let tls = TlsConfig::new(certificate(&localhost))
+            .server_name("Example.test", certificate(&example))
+            .server_name("*.example.test", certificate(&wildcard));
+        let address = spawn_server(tls);
+
+        let (protocol, response) = get(address, "localhost", &localhost).unwrap();
+        assert_eq!(protocol.as_deref(), Some(&b"http/1.1"[..]), "The server did not negotiate HTTP/1.1!");
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n") && response.ends_with("secret"), "{}", response);
+        assert!(get(address, "example.test", &example).is_ok(), "The server name was not matched!");
+        assert!(get(address, "www.example.test", &wildcard).is_ok(), "The wildcard was not matched!");
+        assert!(get(address, "other.test", &example).is_err(), "An unknown name got a named certificate!");
+    }
+
+    #[test]
+    fn reload_replaces_certificates_from_files() {
+        let directory = std::env::temp_dir().join(format!("tls-reload-{}", std::process::id()));
+        std::fs::create_dir_all(&directory).unwrap();
+        let (chain, key) = (directory.join("chain.pem"), directory.join("key.pem"));
+        let (old, new) = (self_signed("localhost"), self_signed("localhost"));
+        std::fs::write(&chain, &old.chain).unwrap();
+        std::fs::write(&key, &old.key).unwrap();
+        let tls = TlsConfig::new(Certificate::from_pem_files(&chain, &key).unwrap());
+        let address = spawn_server(tls.clone());
+        assert!(get(address, "localhost", &old).is_ok());
+
+        std::fs::write(&chain, &new.chain).unwrap();
+        std::fs::write(&key, "not a key").unwrap();
+        assert!(matches!(tls.reload(), Err(TlsError::NoPrivateKey)));
+        assert!(get(address, "localhost", &old).is_ok(), "A failed reload replaced the certificate!");
+
+        std::fs::write(&key, &new.key).unwrap();
+        tls.reload().unwrap();
+        assert!(get(address, "localhost", &new).is_ok(), "The renewed certificate was not used!");
+        assert!(get(address, "localhost", &old).is_err(), "The old certificate was still used!");
+        std::fs::remove_dir_all(&directory).unwrap();
+    }
+}
@@ -0,0 +1,146 @@
+//! Serving over Unix domain sockets, for clients on the same machine such as
+//! a local reverse proxy.
+
+use std::io::ErrorKind;
+use std::net::Shutdown;
+use std::os::unix::fs::{DirBuilderExt, FileTypeExt, PermissionsExt};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::Path;
+use std::time::Duration;
+use tracing::debug;
+use crate::extract::Peer;
+use crate::shutdown::Socket;
+use crate::Stream;
+
+/// Binds a socket at `path`, giving it the permissions `mode`.
+///
+/// A socket already at `path` that nothing accepts on any more is left over
+/// from a server that did not shut down cleanly, and is replaced. A socket
+/// still in use, or a file that is not a socket, is left alone.
+///
+/// With a `mode`, the socket is bound in a directory only the server can
+/// enter and moved to `path` once it has its permissions, so clients never
+/// see it with the permissions the umask gives it.
+pub(crate) fn bind(path: &Path, mode: Option<u32>) -> std::io::Result<UnixListener> {
+    match std::fs::symlink_metadata(path) {
+        Ok(metadata) if metadata.file_type().is_socket() => match UnixStream::connect(path) {
+            Ok(_) => {
+                let message = format!("{} is in use by another server", path.display());
+                return Err(std::io::Error::new(ErrorKind::AddrInUse, message));
+            }
+            Err(e) if e.kind() == ErrorKind::ConnectionRefused => {
+                debug!("Removing stale socket {}", path.display());
+                std::fs::remove_file(path)?;
+            }
+            Err(e) => return Err(e),
+        },
+        Ok(_) => {
+            let message = format!("{} exists and is not a socket", path.display());
+            return Err(std::io::Error::new(ErrorKind::AlreadyExists, message));
+        }
+        Err(e) if e.kind() == ErrorKind::NotFound => {}
+        Err(e) => return Err(e),
+    }
+    match mode {
+        Some(mode) => bind_privately(path, mode),
+        None => UnixListener::bind(path),
+    }
+}
+
+/// Binds a socket with the permissions `mode`, then moves it to `path`.
+fn bind_privately(path: &Path, mode: u32) -> std::io::Result<UnixListener> {
+    let name = path.file_name().ok_or_else(|| {
+        std::io::Error::new(ErrorKind::InvalidInput, format!("{} is not a file path", path.display()))
+    })?;
+    let private = path.with_file_name(format!(".{}.{}", name.to_string_lossy(), std::process::id()));
+    std::fs::DirBuilder::new().mode(0o700).create(&private)?;
+    let staged = private.join("socket");
+    let bound = UnixListener::bind(&staged).and_then(|listener| {
+        std::fs::set_permissions(&staged, std::fs::Permissions::from_mode(mode))?;
+        std::fs::rename(&staged, path)?;
+        Ok(listener)
+    });
+    if bound.is_err() {
+        let _ = std::fs::remove_file(&staged);
+    }
+    let _ = std::fs::remove_dir(&private);
+    bound
+}
+
+impl Stream for UnixStream {
+    fn peer(&self) -> Option<Peer> {
+        let address = self.peer_addr().ok()?;
+        Some(Peer::Unix(address.as_pathname().map(Path::to_path_buf)))
+    }
+
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> std::io::Result<()> {
+        UnixStream::set_read_timeout(self, timeout)
+    }
+
+    fn shutdown_write(&self) {
+        let _ = UnixStream::shutdown(self, Shutdown::Write);
+    }
+}
+
+impl Socket for UnixStream {
+    fn duplicate(&self) -> std::io::Result<Box<dyn Socket>> {
+        Ok(Box::new(self.try_clone()?))
+    }
+
+    fn shutdown(&self, how: Shutdown) -> std::io::Result<()> {
+        UnixStream::shutdown(self, how)
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////////////////////////////
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{Read, Write};
+    use std::thread;
+    use crate::{Router, Server};
+
+    fn connect(path: &Path) -> UnixStream {
+        for _ in 0..50 {
+            if let Ok(stream) = UnixStream::connect(path) {
+                stream.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+                return stream;
+            }
+            thread::sleep(Duration::from_millis(20));
+        }
+        panic!("The server never listened on {}!", path.display());
+    }
+
+    #[test]
+    fn serves_over_unix_sockets() {
+        let directory = std::env::temp_dir().join(format!("unix-serve-{}", std::process::id()));
+        std::fs::create_dir_all(&directory).unwrap();
+        let path = directory.join("server.sock");
+        // A socket left behind by a server that went away without cleaning up.
+        drop(UnixListener::bind(&path).unwrap());
+
+        let router = Router::new().get("/", |peer: Peer| format!("{:?}", peer));
+        let server = Server::unix(&path).socket_mode(0o600).router(router);
+        let handle = server.shutdown_handle();
+        let serving = thread::spawn(move || server.serve());
+
+        let mut stream = connect(&path);
+        let mode = std::fs::metadata(&path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600, "The socket permissions were not set!");
+        assert_eq!(std::fs::read_dir(&directory).unwrap().count(), 1, "The private directory was left behind!");
+        stream.write_all(b"GET / HTTP/1.1\r\nConnection: close\r\n\r\n").unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "{}", response);
+        assert!(response.ends_with("Unix(None)"), "The peer was not a Unix peer!");
+
+        let error = Server::unix(&path).serve().unwrap_err();
+        assert_eq!(error.kind(), ErrorKind::AddrInUse, "A socket in use was replaced!");
+
+        handle.shutdown();
+        serving.join().unwrap().unwrap();
+        assert!(!path.exists(), "The socket was not removed after shutdown!");
+        std::fs::remove_dir_all(&directory).unwrap();
+    }
+}